@@ -0,0 +1,164 @@
+use crate::config::{Hotkey, KeyBinding, Modifier};
+use crate::device::DeviceInfo;
+use evdev::Key;
+use std::collections::HashSet;
+
+/// Tracks the progress through sxhkd style chords such as `super + w ; f`.
+#[derive(Debug, Default)]
+pub struct ChordMatcher {
+    /// The steps typed so far. Empty when no chord is in progress.
+    progress: Vec<KeyBinding>,
+    /// Keys whose press was consumed by a chord, so that their repeats and releases are too.
+    swallowed: HashSet<Key>,
+}
+
+#[derive(Debug)]
+pub enum ChordOutcome {
+    /// The key press doesn't belong to any chord.
+    Unmatched,
+    /// The key press advanced a chord which now waits for its next step.
+    Pending,
+    /// The key press completed a chord.
+    Complete(Box<Hotkey>),
+    /// The abort key was pressed while a chord was in progress.
+    Aborted,
+}
+
+impl ChordMatcher {
+    pub fn is_pending(&self) -> bool {
+        !self.progress.is_empty()
+    }
+
+    pub fn reset(&mut self) {
+        self.progress.clear();
+    }
+
+    /// Feeds a non-modifier key press. A press that doesn't fit the chord in progress drops it
+    /// and is reported as unmatched, so that it can still trigger a plain hotkey.
+    pub fn press(
+        &mut self,
+        key: Key,
+        state_modifiers: &HashSet<Modifier>,
+        device: &DeviceInfo,
+        hotkeys: &[Hotkey],
+        abort_key: Key,
+    ) -> ChordOutcome {
+        if self.is_pending() && key == abort_key {
+            log::debug!("Chord aborted.");
+            self.reset();
+            self.swallowed.insert(key);
+            return ChordOutcome::Aborted;
+        }
+
+        let depth = self.progress.len();
+        let mut next_step = None;
+        for hotkey in hotkeys.iter().filter(|hotkey| {
+            !hotkey.chord.is_empty()
+                && hotkey.matches_device(device)
+                && hotkey.chord.len() >= depth
+                && hotkey.chord[..depth] == self.progress[..]
+        }) {
+            let step = hotkey.chord.get(depth).unwrap_or(&hotkey.keybinding);
+            if step.keysym != key || !step.modifiers_match(state_modifiers) {
+                continue;
+            }
+            if depth == hotkey.chord.len() {
+                self.reset();
+                self.swallowed.insert(key);
+                return ChordOutcome::Complete(Box::new(hotkey.clone()));
+            }
+            next_step = Some(step.clone());
+        }
+
+        match next_step {
+            Some(step) => {
+                self.progress.push(step);
+                self.swallowed.insert(key);
+                ChordOutcome::Pending
+            }
+            None => {
+                if self.is_pending() {
+                    log::debug!("Key doesn't continue the chord, dropping it.");
+                    self.reset();
+                }
+                ChordOutcome::Unmatched
+            }
+        }
+    }
+
+    /// Whether a repeat (`value` 2) or release (`value` 0) of `key` belongs to a press that was
+    /// consumed by a chord.
+    pub fn swallows(&mut self, key: Key, value: i32) -> bool {
+        if value == 0 {
+            self.swallowed.remove(&key)
+        } else {
+            self.swallowed.contains(&key)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> DeviceInfo {
+        DeviceInfo {
+            path: "/dev/input/event0".to_string(),
+            name: "Keyboard".to_string(),
+            phys: String::new(),
+            uniq: String::new(),
+            bus: 3,
+            vendor: 0,
+            product: 0,
+            classes: vec![],
+        }
+    }
+
+    /// `super + w ; f`
+    fn hotkeys() -> Vec<Hotkey> {
+        let mut hotkey = Hotkey::new(Key::KEY_F, [], "firefox".to_string());
+        hotkey.chord = vec![KeyBinding::new(Key::KEY_W, HashSet::from([Modifier::Super]))];
+        vec![hotkey]
+    }
+
+    fn press(matcher: &mut ChordMatcher, key: Key, modifiers: &[Modifier]) -> ChordOutcome {
+        let modifiers = modifiers.iter().copied().collect();
+        matcher.press(key, &modifiers, &device(), &hotkeys(), Key::KEY_ESC)
+    }
+
+    #[test]
+    fn completes_chord() {
+        let mut matcher = ChordMatcher::default();
+        assert!(matches!(
+            press(&mut matcher, Key::KEY_W, &[Modifier::Super]),
+            ChordOutcome::Pending
+        ));
+        assert!(matcher.is_pending());
+        assert!(matcher.swallows(Key::KEY_W, 2));
+        assert!(matcher.swallows(Key::KEY_W, 0));
+        match press(&mut matcher, Key::KEY_F, &[]) {
+            ChordOutcome::Complete(hotkey) => assert_eq!(hotkey.command, "firefox"),
+            outcome => panic!("expected the chord to complete: {:?}", outcome),
+        }
+        assert!(!matcher.is_pending());
+    }
+
+    #[test]
+    fn other_key_drops_chord() {
+        let mut matcher = ChordMatcher::default();
+        press(&mut matcher, Key::KEY_W, &[Modifier::Super]);
+        assert!(matches!(press(&mut matcher, Key::KEY_G, &[]), ChordOutcome::Unmatched));
+        assert!(!matcher.is_pending());
+        assert!(matches!(press(&mut matcher, Key::KEY_F, &[]), ChordOutcome::Unmatched));
+        assert!(!matcher.swallows(Key::KEY_G, 0));
+    }
+
+    #[test]
+    fn abort_key_drops_chord() {
+        let mut matcher = ChordMatcher::default();
+        press(&mut matcher, Key::KEY_W, &[Modifier::Super]);
+        assert!(matches!(press(&mut matcher, Key::KEY_ESC, &[]), ChordOutcome::Aborted));
+        assert!(!matcher.is_pending());
+        assert!(matches!(press(&mut matcher, Key::KEY_ESC, &[]), ChordOutcome::Unmatched));
+    }
+}
@@ -1,12 +1,260 @@
-use std::collections::HashSet;
-use std::path::Path;
+use crate::device::{DeviceInfo, DeviceRules, DeviceSelector};
+use crate::output::{Layout, Output};
+use crate::supervisor::{Environment, Policy, Shell};
+use crate::syntax;
+use crate::taphold::DualRole;
+use evdev::{RelativeAxisType, SwitchType};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{error::Error, fmt, fs, io};
 use sweet::KeyAttribute;
 use sweet::{Definition, SwhkdParser};
 use sweet::{ModeInstruction, ParseError};
 
-pub fn load(path: &Path) -> Result<Vec<Mode>, ParseError> {
-    let config_self = sweet::SwhkdParser::from(sweet::ParserInput::Path(path))?;
-    parse_contents(config_self)
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(ParseError),
+    /// Errors in the swhkd specific syntax that is handled before the config reaches sweet.
+    Syntax {
+        /// The config file, which is either the main one or one it includes.
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(_) => write!(f, "Failed to read config file"),
+            ConfigError::Parse(_) => write!(f, "Failed to parse config file"),
+            ConfigError::Syntax { path, line, message } => {
+                write!(f, "Error in {} at line {}: {}", path.display(), line, message)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+            ConfigError::Syntax { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<ParseError> for ConfigError {
+    fn from(e: ParseError) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub modes: Vec<Mode>,
+    pub settings: Settings,
+    /// `device include` and `device exclude` rules deciding which devices get grabbed.
+    pub device_rules: DeviceRules,
+    /// Keys declared with `tap_hold`.
+    pub dual_roles: Vec<DualRole>,
+    /// Modifiers declared with `modifier`, indexed by `Modifier::Custom`.
+    pub custom_modifiers: Vec<CustomModifier>,
+}
+
+/// Global options set with `set <name> <value>` statements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// How long a started chord waits for its next step. Waits forever when unset.
+    pub chord_timeout: Option<Duration>,
+    /// Key that aborts a started chord.
+    pub chord_abort_key: evdev::Key,
+    /// How long a dual-role key has to be held before it acts as held.
+    pub tap_hold_timeout: Duration,
+    /// The characters `@type` can type, changed with `layout` statements.
+    pub layout: Layout,
+    /// Repeat policy of hotkeys that don't have their own, replacing `--cooldown`.
+    pub repeat: Option<Repeat>,
+    /// How long multi-tap hotkeys wait for the next press of their combo.
+    pub multi_tap_window: Duration,
+    /// How long a modifier can be held and still count as tapped. No limit when unset.
+    pub modifier_tap_timeout: Option<Duration>,
+    /// Whether held modifiers and keys combine across devices.
+    pub input_state: InputState,
+    /// Whether failing commands are reported through `notify-send` as well as the log.
+    pub notify_failures: bool,
+    /// How the commands of bindings without their own shell are run.
+    pub shell: Shell,
+}
+
+/// Where the modifiers and keys that hotkeys are matched against are held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputState {
+    /// On any of the grabbed devices, e.g. super on the keyboard and a key on a macro pad.
+    #[default]
+    Global,
+    /// On the device the key is pressed on.
+    Device,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            chord_timeout: None,
+            chord_abort_key: evdev::Key::KEY_ESC,
+            tap_hold_timeout: Duration::from_millis(200),
+            layout: Layout::default(),
+            repeat: None,
+            multi_tap_window: Duration::from_millis(300),
+            modifier_tap_timeout: Some(Duration::from_millis(500)),
+            input_state: InputState::default(),
+            notify_failures: false,
+            shell: Shell::default(),
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    // Statements that sweet doesn't understand are taken out of every config file before it is
+    // handed over, leaving blank lines behind so that line numbers in errors stay correct.
+    let files = read_files(path)?;
+    let extracted = syntax::extract(&files)?;
+    let mut modes = vec![Mode::default()];
+    for source in &extracted.sources {
+        let parsed = sweet::SwhkdParser::from(sweet::ParserInput::Raw(source))?;
+        merge_modes(&mut modes, parse_contents(parsed)?);
+    }
+
+    for (mode_name, hotkey) in extracted.hotkeys {
+        mode_by_name(&mut modes, mode_name).hotkeys.push(hotkey);
+    }
+
+    for (mode_name, repeat) in extracted.mode_repeats {
+        for mode in modes.iter_mut().filter(|mode| mode.name == mode_name) {
+            for hotkey in mode.hotkeys.iter_mut().filter(|hotkey| hotkey.repeat.is_none()) {
+                hotkey.repeat = Some(repeat);
+            }
+        }
+    }
+
+    // Remaps declared outside of mode blocks apply to every mode that doesn't override them
+    for (mode_name, from, to) in extracted.remaps.iter().filter(|(name, ..)| name.is_some()) {
+        mode_by_name(&mut modes, mode_name.clone()).remaps.insert(*from, *to);
+    }
+    for (_, from, to) in extracted.remaps.iter().filter(|(name, ..)| name.is_none()) {
+        for mode in modes.iter_mut() {
+            mode.remaps.entry(*from).or_insert(*to);
+        }
+    }
+
+    for hotkey in modes.iter_mut().flat_map(|mode| mode.hotkeys.iter_mut()) {
+        hotkey.shell.get_or_insert_with(|| extracted.settings.shell.clone());
+    }
+
+    // Bindings override their mode, and modes the statements outside of mode blocks, which
+    // may in turn override the variables swhkd sets itself
+    let global = extracted.environments.iter().filter(|(name, _)| name.is_none());
+    let global = global.fold(Environment::default(), |mut outer, (_, environment)| {
+        outer.extend(environment);
+        outer
+    });
+    for mode in modes.iter_mut() {
+        let mut outer = global.clone();
+        for (_, environment) in
+            extracted.environments.iter().filter(|(name, _)| name.as_ref() == Some(&mode.name))
+        {
+            outer.extend(environment);
+        }
+        for hotkey in mode.hotkeys.iter_mut() {
+            let mut environment = hotkey.context(&extracted.custom_modifiers);
+            environment.extend(&outer);
+            environment.extend(&hotkey.environment);
+            hotkey.environment = environment;
+            hotkey.label = hotkey.describe(&extracted.custom_modifiers);
+        }
+    }
+
+    // Bindings that are scoped with `@device` themselves keep their own selectors
+    for (mode_name, selectors) in extracted.mode_devices {
+        for mode in modes.iter_mut().filter(|mode| mode.name == mode_name) {
+            for hotkey in mode.hotkeys.iter_mut().filter(|hotkey| hotkey.devices.is_empty()) {
+                hotkey.devices.clone_from(&selectors);
+            }
+        }
+    }
+
+    for (mode_index, mode) in modes.iter_mut().enumerate() {
+        for (index, hotkey) in mode.hotkeys.iter_mut().enumerate() {
+            hotkey.id = (mode_index, index);
+        }
+    }
+
+    Ok(Config {
+        modes,
+        settings: extracted.settings,
+        device_rules: extracted.device_rules,
+        dual_roles: extracted.dual_roles,
+        custom_modifiers: extracted.custom_modifiers,
+    })
+}
+
+/// Reads the config file at `path` followed by the files it includes, directly or through other
+/// included files. Every file is read once, however often it's included.
+fn read_files(path: &Path) -> Result<Vec<(PathBuf, String)>, ConfigError> {
+    let mut files = vec![(path.to_path_buf(), fs::read_to_string(path)?)];
+    let mut i = 0;
+    while i < files.len() {
+        for include in syntax::includes(&files[i].1) {
+            if !files.iter().any(|(path, _)| *path == include) {
+                let contents = fs::read_to_string(&include)?;
+                files.push((include, contents));
+            }
+        }
+        i += 1;
+    }
+    Ok(files)
+}
+
+/// Adds the modes of a config file to those of the files before it. Modes declared in several
+/// files are merged.
+fn merge_modes(modes: &mut Vec<Mode>, file_modes: Vec<Mode>) {
+    for (index, mode) in file_modes.into_iter().enumerate() {
+        let existing = match index {
+            0 => Some(0),
+            _ => modes.iter().skip(1).position(|other| other.name == mode.name).map(|i| i + 1),
+        };
+        match existing {
+            Some(existing) => {
+                modes[existing].hotkeys.extend(mode.hotkeys);
+                modes[existing].unbinds.extend(mode.unbinds);
+            }
+            None => modes.push(mode),
+        }
+    }
+}
+
+/// Finds the mode declared with `name`, adding it if sweet didn't know it. `None` is the
+/// default mode.
+fn mode_by_name(modes: &mut Vec<Mode>, name: Option<String>) -> &mut Mode {
+    let Some(name) = name else {
+        return &mut modes[0];
+    };
+    match modes.iter().position(|mode| mode.name == name) {
+        Some(index) => &mut modes[index],
+        None => {
+            modes.push(Mode { name, ..Default::default() });
+            modes.last_mut().unwrap()
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -15,6 +263,10 @@ pub struct KeyBinding {
     pub modifiers: HashSet<Modifier>,
     pub send: bool,
     pub on_release: bool,
+    /// How long the combo has to be held before the hotkey fires, from `@hold`.
+    pub hold: Option<Duration>,
+    /// How many times the combo has to be pressed in a row, e.g. 2 for `2x super + e`.
+    pub taps: u32,
 }
 
 impl PartialEq for KeyBinding {
@@ -24,6 +276,8 @@ impl PartialEq for KeyBinding {
             && self.modifiers == other.modifiers
             && self.send == other.send
             && self.on_release == other.on_release
+            && self.hold == other.hold
+            && self.taps == other.taps
     }
 }
 
@@ -41,7 +295,22 @@ pub trait Value {
 
 impl KeyBinding {
     pub fn new(keysym: evdev::Key, modifiers: HashSet<Modifier>) -> Self {
-        KeyBinding { keysym, modifiers, send: false, on_release: false }
+        KeyBinding { keysym, modifiers, send: false, on_release: false, hold: None, taps: 1 }
+    }
+
+    /// Checks the held modifiers against the binding. `any` matches as long as one is held.
+    pub fn modifiers_match(&self, state_modifiers: &HashSet<Modifier>) -> bool {
+        !state_modifiers.is_empty() && self.modifiers.contains(&Modifier::Any)
+            || state_modifiers.iter().all(|held| self.modifiers.iter().any(|m| m.matches(*held)))
+                && self
+                    .modifiers
+                    .iter()
+                    .all(|m| state_modifiers.iter().any(|held| m.matches(*held)))
+    }
+
+    /// Whether any of the modifiers of the binding is side specific.
+    pub fn is_sided(&self) -> bool {
+        self.modifiers.iter().any(|modifier| modifier.generic() != *modifier)
     }
 
     pub fn on_release(mut self) -> Self {
@@ -81,6 +350,98 @@ pub struct Hotkey {
     pub keybinding: KeyBinding,
     pub command: String,
     pub mode_instructions: Vec<ModeInstruction>,
+    /// Steps that have to be typed in order before `keybinding`, e.g. `super + w` in
+    /// `super + w ; f`. Empty for plain hotkeys.
+    pub chord: Vec<KeyBinding>,
+    /// Devices the hotkey is limited to. Empty for hotkeys that apply to every device.
+    pub devices: Vec<DeviceSelector>,
+    /// The event that fires the hotkey along with its modifiers.
+    pub trigger: Trigger,
+    /// Synthetic input emitted when the hotkey fires, from `@send`, `@type` and `@macro`.
+    pub outputs: Vec<Output>,
+    /// How the hotkey repeats while held. Falls back to the policy of its mode and then the
+    /// global one.
+    pub repeat: Option<Repeat>,
+    /// What firing the hotkey does while its command is still running, from `@single` and
+    /// `@toggle`.
+    pub policy: Policy,
+    /// How the command is run, from `@shell` or `@exec`. Set to the global shell when loading.
+    pub shell: Option<Shell>,
+    /// Variables and working directory of the command, from `@env`, `@unset` and `@cwd` along
+    /// with those of its mode. Resolved when loading.
+    pub environment: Environment,
+    /// A readable name of the hotkey for logs and replies, see `Hotkey::describe`. Resolved when
+    /// loading.
+    pub label: String,
+    /// The index of the mode and of the hotkey in it. Assigned when loading, so it's only valid
+    /// for the config the hotkey belongs to.
+    pub id: (usize, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repeat {
+    Off,
+    /// Repeats after `delay` and then every `interval`.
+    Every {
+        delay: Duration,
+        interval: Duration,
+    },
+    /// Repeats with the autorepeat events the kernel sends for the held key.
+    Kernel,
+    /// Repeats at the key repeat rate of the device the hotkey was pressed on.
+    Device,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Trigger {
+    /// A press of the key of the keybinding.
+    #[default]
+    Key,
+    /// A detent of a scroll wheel or dial.
+    Scroll(Scroll),
+    /// A hardware switch turning on or off, e.g. the lid closing for `SW_LID`.
+    Switch { switch: SwitchType, on: bool },
+    /// A modifier pressed and released with no other key pressed in between.
+    ModifierTap(Modifier),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scroll {
+    Up,
+    Down,
+    Left,
+    Right,
+    DialCw,
+    DialCcw,
+}
+
+impl Scroll {
+    /// The scroll direction of a relative axis event, for both the regular and the high
+    /// resolution wheel axes.
+    pub fn from_event(axis: RelativeAxisType, value: i32) -> Option<Self> {
+        let positive = match value {
+            0 => return None,
+            value => value > 0,
+        };
+        match axis {
+            RelativeAxisType::REL_WHEEL | RelativeAxisType::REL_WHEEL_HI_RES => {
+                Some(if positive { Scroll::Up } else { Scroll::Down })
+            }
+            RelativeAxisType::REL_HWHEEL | RelativeAxisType::REL_HWHEEL_HI_RES => {
+                Some(if positive { Scroll::Right } else { Scroll::Left })
+            }
+            RelativeAxisType::REL_DIAL => {
+                Some(if positive { Scroll::DialCw } else { Scroll::DialCcw })
+            }
+            _ => None,
+        }
+    }
+
+    /// High resolution axes report fractions of a detent. Devices that have them send the
+    /// regular axis as well whenever a full detent is reached.
+    pub fn is_hi_res(axis: RelativeAxisType) -> bool {
+        axis == RelativeAxisType::REL_WHEEL_HI_RES || axis == RelativeAxisType::REL_HWHEEL_HI_RES
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
@@ -91,11 +452,148 @@ pub enum Modifier {
     Control,
     Shift,
     Any,
+    LeftSuper,
+    RightSuper,
+    LeftControl,
+    RightControl,
+    LeftShift,
+    RightShift,
+    /// A modifier declared in the config, by its index in `Config::custom_modifiers`.
+    Custom(usize),
+}
+
+impl fmt::Display for Modifier {
+    /// The config name of the modifier. User-defined modifiers only know their index here, use
+    /// `Modifier::name` to show them.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Modifier::Super => write!(f, "super"),
+            Modifier::Alt => write!(f, "alt"),
+            Modifier::Altgr => write!(f, "altgr"),
+            Modifier::Control => write!(f, "ctrl"),
+            Modifier::Shift => write!(f, "shift"),
+            Modifier::Any => write!(f, "any"),
+            Modifier::LeftSuper => write!(f, "lsuper"),
+            Modifier::RightSuper => write!(f, "rsuper"),
+            Modifier::LeftControl => write!(f, "lctrl"),
+            Modifier::RightControl => write!(f, "rctrl"),
+            Modifier::LeftShift => write!(f, "lshift"),
+            Modifier::RightShift => write!(f, "rshift"),
+            Modifier::Custom(index) => write!(f, "custom{}", index),
+        }
+    }
+}
+
+/// A modifier declared with `modifier <name> = <key>...`, e.g. `modifier hyper = capslock`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomModifier {
+    pub name: String,
+    pub keys: Vec<evdev::Key>,
+    /// Whether the keys are kept from applications.
+    pub swallow: bool,
+}
+
+impl Modifier {
+    pub fn from_sweet(modifier: &sweet::Modifier) -> Option<Self> {
+        match modifier {
+            sweet::Modifier::Super => Some(Modifier::Super),
+            sweet::Modifier::Any => Some(Modifier::Any),
+            sweet::Modifier::Control => Some(Modifier::Control),
+            sweet::Modifier::Alt => Some(Modifier::Alt),
+            sweet::Modifier::Altgr => Some(Modifier::Altgr),
+            sweet::Modifier::Shift => Some(Modifier::Shift),
+            sweet::Modifier::Omission => None,
+        }
+    }
+
+    /// The config name of the modifier, including the names of user-defined ones.
+    pub fn name(self, custom: &[CustomModifier]) -> String {
+        match self {
+            Modifier::Custom(index) if index < custom.len() => custom[index].name.clone(),
+            modifier => modifier.to_string(),
+        }
+    }
+
+    /// The modifier without its side, e.g. `Super` for `LeftSuper`.
+    pub fn generic(self) -> Self {
+        match self {
+            Modifier::LeftSuper | Modifier::RightSuper => Modifier::Super,
+            Modifier::LeftControl | Modifier::RightControl => Modifier::Control,
+            Modifier::LeftShift | Modifier::RightShift => Modifier::Shift,
+            modifier => modifier,
+        }
+    }
+
+    /// Whether the modifier of a binding is satisfied by a held one. Generic modifiers are held
+    /// through either of their keys, sided ones only through their own.
+    pub fn matches(self, held: Modifier) -> bool {
+        self == held || self == held.generic()
+    }
 }
 
 impl Hotkey {
     pub fn from_keybinding(keybinding: KeyBinding, command: String) -> Self {
-        Hotkey { keybinding, command, mode_instructions: vec![] }
+        Hotkey {
+            keybinding,
+            command,
+            mode_instructions: vec![],
+            chord: vec![],
+            devices: vec![],
+            trigger: Trigger::Key,
+            outputs: vec![],
+            repeat: None,
+            policy: Policy::default(),
+            shell: None,
+            environment: Environment::default(),
+            label: String::new(),
+            id: (0, 0),
+        }
+    }
+
+    pub fn matches_device(&self, device: &DeviceInfo) -> bool {
+        self.devices.is_empty() || self.devices.iter().any(|selector| selector.matches(device))
+    }
+
+    /// A readable name of the hotkey, e.g. `super + shift + KEY_E`. Custom modifiers are named
+    /// as declared in the config.
+    pub fn describe(&self, custom: &[CustomModifier]) -> String {
+        let last = match self.trigger {
+            Trigger::Key => format!("{:?}", self.keybinding.keysym),
+            Trigger::Scroll(scroll) => format!("scroll_{:?}", scroll).to_lowercase(),
+            Trigger::Switch { switch, on } => {
+                format!("switch {:?} {}", switch, if on { "on" } else { "off" })
+            }
+            Trigger::ModifierTap(modifier) => modifier.name(custom),
+        };
+        let steps =
+            self.chord.iter().map(|step| combo_label(step, format!("{:?}", step.keysym), custom));
+        let mut label: Vec<String> = steps.collect();
+        label.push(combo_label(&self.keybinding, last, custom));
+        label.join(" ; ")
+    }
+
+    /// The variables that tell the command which key and modifiers fired it, so that bindings
+    /// expanded from `{a,b,c}` can tell each other apart.
+    pub fn context(&self, custom: &[CustomModifier]) -> Environment {
+        let key = match self.trigger {
+            Trigger::Key => {
+                let name = format!("{:?}", self.keybinding.keysym);
+                name.strip_prefix("KEY_").unwrap_or(&name).to_lowercase()
+            }
+            Trigger::Scroll(scroll) => format!("scroll_{:?}", scroll).to_lowercase(),
+            Trigger::Switch { switch, .. } => format!("{:?}", switch).to_lowercase(),
+            Trigger::ModifierTap(modifier) => modifier.name(custom),
+        };
+        let mut modifiers: Vec<String> =
+            self.keybinding.modifiers.iter().map(|modifier| modifier.name(custom)).collect();
+        modifiers.sort();
+        Environment {
+            vars: vec![
+                ("SWHKD_KEY".to_string(), Some(key)),
+                ("SWHKD_MODIFIERS".to_string(), Some(modifiers.join(" "))),
+            ],
+            dir: None,
+        }
     }
 
     /// Accepts both Vec<Modifier> and HashSet<Modifier> and stored as HashSet<Modifier>
@@ -105,11 +603,19 @@ impl Hotkey {
         modifiers: impl IntoIterator<Item = Modifier>,
         command: String,
     ) -> Self {
-        Hotkey {
-            keybinding: KeyBinding::new(keysym, modifiers.into_iter().collect()),
-            command,
-            mode_instructions: vec![],
-        }
+        Hotkey::from_keybinding(KeyBinding::new(keysym, modifiers.into_iter().collect()), command)
+    }
+}
+
+fn combo_label(keybinding: &KeyBinding, last: String, custom: &[CustomModifier]) -> String {
+    let mut parts: Vec<String> =
+        keybinding.modifiers.iter().map(|modifier| modifier.name(custom)).collect();
+    parts.sort();
+    parts.push(last);
+    let combo = parts.join(" + ");
+    match keybinding.taps {
+        1 => combo,
+        taps => format!("{}x {}", taps, combo),
     }
 }
 
@@ -145,6 +651,8 @@ pub struct Mode {
     pub hotkeys: Vec<Hotkey>,
     pub unbinds: Vec<KeyBinding>,
     pub options: ModeOptions,
+    /// Keys that are replaced by other keys while the mode is active.
+    pub remaps: HashMap<evdev::Key, evdev::Key>,
 }
 
 impl Default for Mode {
@@ -154,6 +662,7 @@ impl Default for Mode {
             hotkeys: vec![],
             unbinds: vec![],
             options: ModeOptions::default(),
+            remaps: HashMap::new(),
         }
     }
 }
@@ -169,9 +678,8 @@ pub fn parse_contents(contents: SwhkdParser) -> Result<Vec<Mode>, ParseError> {
 
     for binding in &contents.bindings {
         default_mode.hotkeys.push(Hotkey {
-            keybinding: sweet_def_to_kb(&binding.definition),
-            command: binding.command.clone(),
             mode_instructions: binding.mode_instructions.clone(),
+            ..Hotkey::from_keybinding(sweet_def_to_kb(&binding.definition), binding.command.clone())
         });
     }
     default_mode.unbinds.extend(contents.unbinds.iter().map(sweet_def_to_kb));
@@ -184,9 +692,8 @@ pub fn parse_contents(contents: SwhkdParser) -> Result<Vec<Mode>, ParseError> {
 
         for binding in bindings {
             let hotkey = Hotkey {
-                keybinding: sweet_def_to_kb(&binding.definition),
-                command: binding.command,
                 mode_instructions: binding.mode_instructions.clone(),
+                ..Hotkey::from_keybinding(sweet_def_to_kb(&binding.definition), binding.command)
             };
             // Replace existing hotkeys with same keybinding
             pushmode.hotkeys.retain(|h| h.keybinding.keysym != hotkey.keybinding.keysym);
@@ -201,24 +708,39 @@ pub fn parse_contents(contents: SwhkdParser) -> Result<Vec<Mode>, ParseError> {
 
 /// Convert sweet::Definition to KeyBinding
 fn sweet_def_to_kb(def: &Definition) -> KeyBinding {
-    let modifiers: HashSet<Modifier> = def
-        .modifiers
-        .iter()
-        .filter_map(|m| match m {
-            sweet::Modifier::Super => Some(Modifier::Super),
-            sweet::Modifier::Any => Some(Modifier::Any),
-            sweet::Modifier::Control => Some(Modifier::Control),
-            sweet::Modifier::Alt => Some(Modifier::Alt),
-            sweet::Modifier::Altgr => Some(Modifier::Altgr),
-            sweet::Modifier::Shift => Some(Modifier::Shift),
-            sweet::Modifier::Omission => None,
-        })
-        .collect();
+    let modifiers: HashSet<Modifier> =
+        def.modifiers.iter().filter_map(Modifier::from_sweet).collect();
 
     KeyBinding {
         keysym: def.key.key,
         modifiers,
         send: def.key.attribute == KeyAttribute::Send,
         on_release: def.key.attribute == KeyAttribute::OnRelease,
+        hold: None,
+        taps: 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sided_and_any_modifiers_match_held_ones() {
+        let held = |modifiers: &[Modifier]| modifiers.iter().copied().collect::<HashSet<_>>();
+        let generic = KeyBinding::new(evdev::Key::KEY_A, held(&[Modifier::Super]));
+        assert!(generic.modifiers_match(&held(&[Modifier::LeftSuper])));
+        assert!(generic.modifiers_match(&held(&[Modifier::RightSuper])));
+        assert!(!generic.modifiers_match(&held(&[Modifier::LeftSuper, Modifier::LeftShift])));
+
+        let sided = KeyBinding::new(evdev::Key::KEY_A, held(&[Modifier::LeftSuper]));
+        assert!(sided.modifiers_match(&held(&[Modifier::LeftSuper])));
+        assert!(!sided.modifiers_match(&held(&[Modifier::RightSuper])));
+        assert!(!sided.modifiers_match(&held(&[])));
+
+        let any = KeyBinding::new(evdev::Key::KEY_A, held(&[Modifier::Any]));
+        assert!(any.modifiers_match(&held(&[Modifier::RightControl])));
+        assert!(any.modifiers_match(&held(&[Modifier::Alt, Modifier::Custom(0)])));
+        assert!(!any.modifiers_match(&held(&[])));
     }
 }
@@ -0,0 +1,176 @@
+use std::{
+    fmt::Display,
+    fs::{self, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+
+/// Root owned socket used by `swhkdctl` to steer the running daemon.
+pub const CONTROL_SOCKET_PATH: &str = "/run/swhkd/control.sock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Reload,
+    Pause,
+    Resume,
+    Status,
+    ListDevices,
+    ShowModeStack,
+    ListProcesses,
+}
+
+impl Request {
+    fn parse(line: &str) -> Option<Self> {
+        match line.trim() {
+            "reload" => Some(Request::Reload),
+            "pause" => Some(Request::Pause),
+            "resume" => Some(Request::Resume),
+            "status" => Some(Request::Status),
+            "list-devices" => Some(Request::ListDevices),
+            "show-mode-stack" => Some(Request::ShowModeStack),
+            "list-processes" => Some(Request::ListProcesses),
+            _ => None,
+        }
+    }
+}
+
+/// A structured reply sent back to the client as a single line of JSON.
+#[derive(Debug)]
+pub struct Reply {
+    ok: bool,
+    body: String,
+}
+
+impl Reply {
+    /// `data` must already be a valid JSON value.
+    pub fn ok(data: String) -> Self {
+        Reply { ok: true, body: data }
+    }
+
+    pub fn error(message: impl Display) -> Self {
+        Reply { ok: false, body: json_string(&message.to_string()) }
+    }
+
+    fn to_json(&self) -> String {
+        if self.ok {
+            format!("{{\"ok\":true,\"data\":{}}}", self.body)
+        } else {
+            format!("{{\"ok\":false,\"error\":{}}}", self.body)
+        }
+    }
+}
+
+pub type Message = (Request, oneshot::Sender<Reply>);
+
+/// Quotes and escapes a string as a JSON string literal.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Joins already encoded JSON values into a JSON array.
+pub fn json_array(values: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", values.into_iter().collect::<Vec<_>>().join(","))
+}
+
+/// Binds a Unix socket at `path`, replacing a stale one, and applies the given permissions.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+        fs::set_permissions(dir, Permissions::from_mode(0o755))?;
+    }
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Binds the control socket and forwards every request to the main loop through `tx`.
+pub fn listen(path: &str, tx: mpsc::Sender<Message>) -> io::Result<()> {
+    let path = Path::new(path);
+    let listener = bind(path, 0o600)?;
+    log::debug!("Listening for control requests on {}", path.display());
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("Failed to accept control connection: {}", e);
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(stream, tx).await {
+                    log::error!("Control connection failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle_client(stream: UnixStream, tx: mpsc::Sender<Message>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        log::debug!("Received control request: {}", line.trim());
+
+        let reply = match Request::parse(&line) {
+            Some(request) => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                if tx.send((request, reply_tx)).await.is_err() {
+                    Reply::error("daemon is shutting down")
+                } else {
+                    reply_rx.await.unwrap_or_else(|_| Reply::error("no reply from daemon"))
+                }
+            }
+            None => Reply::error(format!("unknown command: {}", line.trim())),
+        };
+
+        writer.write_all(reply.to_json().as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("a \"b\" \\ c\nd\te"), r#""a \"b\" \\ c\nd\te""#);
+        assert_eq!(json_string("\u{1}"), r#""\u0001""#);
+        assert_eq!(Reply::error("no").to_json(), r#"{"ok":false,"error":"no"}"#);
+        assert_eq!(Request::parse(" list-processes\n"), Some(Request::ListProcesses));
+    }
+}
@@ -1,7 +1,8 @@
+use crate::chord::{ChordMatcher, ChordOutcome};
 use crate::config::Value;
 use clap::Parser;
 use config::Hotkey;
-use evdev::{AttributeSet, Device, InputEventKind, Key};
+use evdev::{AttributeSet, Device, EventStream, InputEvent, InputEventKind, Key};
 use nix::{
     sys::stat::{umask, Mode},
     unistd::{setgid, setuid, Gid, Uid},
@@ -9,7 +10,7 @@ use nix::{
 use signal_hook::consts::signal::*;
 use signal_hook_tokio::Signals;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     env,
     error::Error,
     fs::{self, File, OpenOptions, Permissions},
@@ -27,19 +28,44 @@ use tokio::{select, sync::mpsc};
 use tokio_stream::{StreamExt, StreamMap};
 use tokio_udev::{AsyncMonitorSocket, EventType, MonitorBuilder};
 
+mod chord;
 mod config;
+mod control;
+mod device;
 mod environ;
+mod events;
+mod multitap;
+mod output;
 mod perms;
+mod remap;
+mod supervisor;
+mod syntax;
+mod taphold;
 mod uinput;
 
 struct KeyboardState {
     state_modifiers: HashSet<config::Modifier>,
     state_keysyms: AttributeSet<evdev::Key>,
+    device: device::DeviceInfo,
+    /// The key repeat rate of the device, as read with EVIOCGREP when it was opened.
+    auto_repeat: Option<config::Repeat>,
 }
 
 impl KeyboardState {
-    fn new() -> KeyboardState {
-        KeyboardState { state_modifiers: HashSet::new(), state_keysyms: AttributeSet::new() }
+    fn new(device: device::DeviceInfo, evdev_device: &Device) -> KeyboardState {
+        let auto_repeat =
+            evdev_device.get_auto_repeat().filter(|rate| rate.period > 0).map(|rate| {
+                config::Repeat::Every {
+                    delay: Duration::from_millis(rate.delay.into()),
+                    interval: Duration::from_millis(rate.period.into()),
+                }
+            });
+        KeyboardState {
+            state_modifiers: HashSet::new(),
+            state_keysyms: AttributeSet::new(),
+            device,
+            auto_repeat,
+        }
     }
 }
 
@@ -63,6 +89,14 @@ struct Args {
     #[arg(short = 'D', long, num_args = 0.., value_delimiter = ' ')]
     device: Vec<String>,
 
+    /// Grab the devices matched by a selector, e.g. `name:"*Keyboard*"` or `bus:usb`. Can occur multiple times.
+    #[arg(long, value_name = "SELECTOR")]
+    include_device: Vec<String>,
+
+    /// Never grab the devices matched by a selector, e.g. `id:1050`. Can occur multiple times.
+    #[arg(long, value_name = "SELECTOR")]
+    exclude_device: Vec<String>,
+
     /// Set a custom log file. (Defaults to ${XDG_DATA_HOME:-$HOME/.local/share}/swhks-current_unix_time.log)
     #[arg(short, long, value_name = "FILE")]
     log: Option<PathBuf>,
@@ -149,7 +183,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Set up a channel to communicate with the server
     // The channel can have upto 100 commands in the queue
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<String>(100);
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<supervisor::Job>(100);
 
     // We use a arc mutex to make sure that our pairs are valid and also concurrent
     // while being used by the threads.
@@ -157,6 +191,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let pairs_clone = Arc::clone(&pairs);
     let log = log_path.clone();
 
+    let supervisor = supervisor::Supervisor::new(supervisor::Session {
+        uid: invoking_uid,
+        gid: invoking_uid,
+        env: Arc::clone(&pairs),
+    });
+    let executor_supervisor = supervisor.clone();
+
     // We spawn a new thread in the user space to act as the execution thread
     // This again has a thread for running the env refresh module when a change is detected from
     // the server.
@@ -189,9 +230,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         // When we do receive a command, we spawn a new thread to execute the command
         // This thread is spawned in the user space and is used to execute the command and it
         // exits after the command is executed.
-        while let Some(command) = rx.recv().await {
-            // Clone the arc references to be used in the thread
-            let pairs = pairs.clone();
+        while let Some(job) = rx.recv().await {
             let log = log.clone();
 
             // Set the user and group id to the invoking user for the thread
@@ -199,10 +238,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
             setuid(Uid::from_raw(invoking_uid)).unwrap();
 
             // Command execution
-            let mut cmd = Command::new("sh");
-            cmd.arg("-c")
-                .arg(command)
-                .stdin(Stdio::null())
+            let mut cmd = match job.command() {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    executor_supervisor
+                        .fail(&job, format!("Invalid command of hotkey {}: {}", job.hotkey, e));
+                    continue;
+                }
+            };
+            cmd.stdin(Stdio::null())
                 .stdout(match File::open(&log) {
                     Ok(file) => file,
                     Err(e) => {
@@ -220,17 +264,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     }
                 });
 
-            // Set the environment variables for the command
-            for (key, value) in pairs.lock().unwrap().iter() {
-                cmd.env(key, value);
-            }
-
-            match cmd.spawn() {
-                Ok(_) => {
-                    log::info!("Command executed successfully.");
-                }
-                Err(e) => log::error!("Failed to execute command: {}", e),
-            }
+            executor_supervisor.spawn(&job, cmd);
         }
     });
 
@@ -240,34 +274,59 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let config_file_path: PathBuf =
         args.config.as_ref().map_or_else(|| env.fetch_xdg_config_path(), |file| file.clone());
-    let load_config = || {
+    let parse_selectors = |selectors: &[String]| -> Vec<device::DeviceSelector> {
+        selectors
+            .iter()
+            .map(|selector| {
+                device::DeviceSelector::parse(selector).unwrap_or_else(|e| {
+                    log::error!("{}", e);
+                    exit(1)
+                })
+            })
+            .collect()
+    };
+    // Device rules from the command line are kept across config reloads
+    let cli_device_rules = device::DeviceRules {
+        include: args
+            .device
+            .iter()
+            .map(|name| device::DeviceSelector::Name(name.clone()))
+            .chain(parse_selectors(&args.include_device))
+            .collect(),
+        exclude: parse_selectors(&args.exclude_device),
+        ..Default::default()
+    };
+    let try_load_config = || {
         log::debug!("Using config file path: {:#?}", config_file_path);
-
-        match config::load(&config_file_path) {
-            Err(e) => {
-                log::error!("Config Error: {}", e);
-                if let Some(error_source) = e.source() {
-                    log::error!("{}", error_source);
-                }
-                exit(1)
+        config::load(&config_file_path).map(|mut config| {
+            config.device_rules.extend(cli_device_rules.clone());
+            config
+        })
+    };
+    let load_config = || match try_load_config() {
+        Err(e) => {
+            log::error!("Config Error: {}", e);
+            if let Some(error_source) = e.source() {
+                log::error!("{}", error_source);
             }
-            Ok(out) => out,
+            exit(1)
         }
+        Ok(out) => out,
     };
 
-    let mut modes = load_config();
+    let mut config = load_config();
+    supervisor.set_notify_failures(config.settings.notify_failures);
     let mut mode_stack: Vec<usize> = vec![0];
-    let arg_devices: Vec<String> = args.device;
 
     let keyboard_devices: Vec<_> = {
-        if arg_devices.is_empty() {
-            log::trace!("Attempting to find all keyboard file descriptors.");
-            evdev::enumerate().filter(|(_, dev)| check_device_is_keyboard(dev)).collect()
-        } else {
-            evdev::enumerate()
-                .filter(|(_, dev)| arg_devices.contains(&dev.name().unwrap_or("").to_string()))
-                .collect()
-        }
+        log::trace!("Attempting to find all keyboard file descriptors.");
+        evdev::enumerate()
+            .filter(|(path, dev)| {
+                config
+                    .device_rules
+                    .should_grab(dev, &device::DeviceInfo::new(&path.to_string_lossy(), dev))
+            })
+            .collect()
     };
 
     if keyboard_devices.is_empty() {
@@ -279,10 +338,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Apparently, having a single uinput device with keys, relative axes and switches
     // prevents some libraries to listen to these events. The easy fix is to have separate
-    // virtual devices, one for keys and relative axes (`uinput_device`) and another one
+    // virtual devices, one for keys and relative axes (`output`) and another one
     // just for switches (`uinput_switches_device`).
-    let mut uinput_device = match uinput::create_uinput_device() {
-        Ok(dev) => dev,
+    let output = match uinput::create_uinput_device() {
+        Ok(dev) => output::Writer::spawn(dev),
         Err(e) => {
             log::error!("Failed to create uinput device: \nErr: {:#?}", e);
             exit(1);
@@ -300,27 +359,42 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut udev =
         AsyncMonitorSocket::new(MonitorBuilder::new()?.match_subsystem("input")?.listen()?)?;
 
-    let modifiers_map: HashMap<Key, config::Modifier> = HashMap::from([
-        (Key::KEY_LEFTMETA, config::Modifier::Super),
-        (Key::KEY_RIGHTMETA, config::Modifier::Super),
-        (Key::KEY_LEFTALT, config::Modifier::Alt),
-        (Key::KEY_RIGHTALT, config::Modifier::Altgr),
-        (Key::KEY_LEFTCTRL, config::Modifier::Control),
-        (Key::KEY_RIGHTCTRL, config::Modifier::Control),
-        (Key::KEY_LEFTSHIFT, config::Modifier::Shift),
-        (Key::KEY_RIGHTSHIFT, config::Modifier::Shift),
-    ]);
+    let mut modifiers_map = build_modifiers_map(&config.custom_modifiers);
 
-    let repeat_cooldown_duration: u64 = args.cooldown;
+    let repeat_cooldown_duration = Duration::from_millis(args.cooldown);
+    // Used by hotkeys that neither they, their mode nor the config set a repeat policy for
+    let default_repeat = config::Repeat::Every {
+        delay: repeat_cooldown_duration,
+        interval: repeat_cooldown_duration,
+    };
 
     let mut signals = Signals::new([
         SIGUSR1, SIGUSR2, SIGHUP, SIGABRT, SIGBUS, SIGCONT, SIGINT, SIGPIPE, SIGQUIT, SIGSYS,
         SIGTERM, SIGTRAP, SIGTSTP, SIGVTALRM, SIGXCPU, SIGXFSZ,
     ])?;
 
+    let (control_tx, mut control_rx) = mpsc::channel::<control::Message>(16);
+    if let Err(e) = control::listen(control::CONTROL_SOCKET_PATH, control_tx) {
+        log::error!("Failed to set up control socket: {}", e);
+    }
+
+    let event_bus = events::EventBus::new(&config.modes, &mode_stack);
+    if let Err(e) = events::listen(events::EVENTS_SOCKET_PATH, event_bus.clone()) {
+        log::error!("Failed to set up events socket: {}", e);
+    }
+    let effects = Effects { event_bus, output, jobs: tx };
+
     let mut execution_is_paused = false;
-    let mut last_hotkey: Option<config::Hotkey> = None;
+    // The hotkey that fired last and is still held, along with the name of its device
+    let mut last_hotkey: Option<(config::Hotkey, String)> = None;
     let mut pending_release: bool = false;
+    let mut chord_matcher = ChordMatcher::default();
+    let mut remapper = remap::Remapper::default();
+    let mut tap_hold = taphold::TapHold::new(&config.dual_roles);
+    // The device of the buffered dual-role key press
+    let mut tap_hold_node = String::new();
+    // Key events produced by dual-role keys, handled before reading the devices again
+    let mut replayed_events: VecDeque<(String, InputEvent, bool)> = VecDeque::new();
     let mut keyboard_states = HashMap::new();
     let mut keyboard_stream_map = StreamMap::new();
 
@@ -332,60 +406,109 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 continue;
             }
         };
-        keyboard_states.insert(path.to_string(), KeyboardState::new());
+        keyboard_states.insert(
+            path.to_string(),
+            KeyboardState::new(device::DeviceInfo::new(path, &device), &device),
+        );
         keyboard_stream_map.insert(path.to_string(), device.into_event_stream()?);
     }
 
     // The initial sleep duration is never read because last_hotkey is initialized to None
     let hotkey_repeat_timer = sleep(Duration::from_millis(0));
     tokio::pin!(hotkey_repeat_timer);
+    // How often the held hotkey repeats, None if it doesn't or the kernel repeats it
+    let mut hotkey_repeat_interval: Option<Duration> = None;
+    // Whether the held hotkey repeats with the autorepeat events of its key
+    let mut hotkey_kernel_repeat = false;
+
+    // A long-press hotkey waiting for its key to be held long enough, along with the hotkey of
+    // the same combo that fires instead if the key is released before and the name of the device
+    let mut long_press: Option<(Hotkey, Option<Hotkey>, String)> = None;
+    let long_press_timer = sleep(Duration::from_millis(0));
+    tokio::pin!(long_press_timer);
+
+    // The modifier pressed on its own, with the device it was pressed on, the other modifiers
+    // held at the time and when it was pressed
+    let mut modifier_tap: Option<(String, config::Modifier, HashSet<config::Modifier>, Instant)> =
+        None;
+
+    let mut tap_counter = multitap::TapCounter::default();
+    let multi_tap_timer = sleep(Duration::from_millis(0));
+    tokio::pin!(multi_tap_timer);
+
+    // Only polled while a chord is in progress and a chord timeout is set
+    let chord_timer = sleep(Duration::from_millis(0));
+    tokio::pin!(chord_timer);
+
+    // Only polled while the press of a dual-role key is buffered
+    let tap_hold_timer = sleep(Duration::from_millis(0));
+    tokio::pin!(tap_hold_timer);
 
     loop {
         select! {
-            _ = &mut hotkey_repeat_timer, if &last_hotkey.is_some() => {
-                let hotkey = last_hotkey.clone().unwrap();
-                if hotkey.keybinding.on_release {
-                    continue;
+            _ = &mut hotkey_repeat_timer, if last_hotkey.is_some() && hotkey_repeat_interval.is_some() => {
+                let (hotkey, device) = last_hotkey.clone().unwrap();
+                send_command(hotkey, &device, &config.modes, &mut mode_stack, &effects).await;
+                hotkey_repeat_timer.as_mut().reset(Instant::now() + hotkey_repeat_interval.unwrap());
+            }
+
+
+
+            _ = &mut long_press_timer, if long_press.is_some() => {
+                let (hotkey, _, device) = long_press.take().unwrap();
+                send_command(hotkey, &device, &config.modes, &mut mode_stack, &effects).await;
+            }
+
+            _ = &mut multi_tap_timer, if tap_counter.is_pending() => {
+                if let Some((hotkey, device)) = tap_counter.finish() {
+                    send_command(hotkey, &device, &config.modes, &mut mode_stack, &effects).await;
                 }
-                send_command(hotkey.clone(), &modes, &mut mode_stack, tx.clone()).await;
-                hotkey_repeat_timer.as_mut().reset(Instant::now() + Duration::from_millis(repeat_cooldown_duration));
             }
 
+            _ = &mut chord_timer, if chord_matcher.is_pending() && config.settings.chord_timeout.is_some() => {
+                log::debug!("Chord timed out.");
+                chord_matcher.reset();
+            }
 
+            _ = &mut tap_hold_timer, if tap_hold.is_pending() => {
+                let actions = tap_hold.resolve_hold();
+                apply_tap_hold_actions(actions, &tap_hold_node, &mut replayed_events, &config.modes, &mut mode_stack, &effects.event_bus);
+            }
 
             Some(signal) = signals.next() => {
                 match signal {
                     SIGUSR1 => {
                         execution_is_paused = true;
-                        for mut device in evdev::enumerate().map(|(_, device)| device).filter(check_device_is_keyboard) {
-                            let _ = device.ungrab();
+                        // Hotkeys that are held or waiting on a timer don't fire while paused
+                        last_hotkey = None;
+                        pending_release = false;
+                        long_press = None;
+                        tap_counter = multitap::TapCounter::default();
+                        if let Err(e) = set_grabbed(&mut keyboard_stream_map, false) {
+                            log::error!("Failed to release devices: {}", e);
                         }
                     }
 
                     SIGUSR2 => {
                         execution_is_paused = false;
-                        for mut device in evdev::enumerate().map(|(_, device)| device).filter(check_device_is_keyboard) {
-                            let _ = device.grab();
+                        if let Err(e) = set_grabbed(&mut keyboard_stream_map, true) {
+                            log::error!("Failed to grab devices: {}", e);
                         }
                     }
 
                     SIGHUP => {
-                        modes = load_config();
-                        mode_stack = vec![0];
+                        let releases = apply_config(load_config(), &mut config, &mut tap_hold, &mut modifiers_map, &mut mode_stack, &effects.event_bus, &supervisor);
+                        apply_tap_hold_actions(releases, &tap_hold_node, &mut replayed_events, &config.modes, &mut mode_stack, &effects.event_bus);
                     }
 
                     SIGINT => {
-                        for mut device in evdev::enumerate().map(|(_, device)| device).filter(check_device_is_keyboard) {
-                            let _ = device.ungrab();
-                        }
+                        let _ = set_grabbed(&mut keyboard_stream_map, false);
                         log::warn!("Received SIGINT signal, exiting...");
                         exit(1);
                     }
 
                     _ => {
-                        for mut device in evdev::enumerate().map(|(_, device)| device).filter(check_device_is_keyboard) {
-                            let _ = device.ungrab();
-                        }
+                        let _ = set_grabbed(&mut keyboard_stream_map, false);
 
                         log::warn!("Received signal: {:#?}", signal);
                         log::warn!("Exiting...");
@@ -394,6 +517,69 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 }
             }
 
+            Some((request, reply_tx)) = control_rx.recv() => {
+                let reply = match request {
+                    control::Request::Reload => match try_load_config() {
+                        Ok(new) => {
+                            let restart_needed = new.device_rules != config.device_rules;
+                            let releases = apply_config(new, &mut config, &mut tap_hold, &mut modifiers_map, &mut mode_stack, &effects.event_bus, &supervisor);
+                            apply_tap_hold_actions(releases, &tap_hold_node, &mut replayed_events, &config.modes, &mut mode_stack, &effects.event_bus);
+                            log::info!("Config reloaded.");
+                            if restart_needed {
+                                control::Reply::ok(control::json_string("reloaded, restart swhkd to apply the changed device rules to the current devices"))
+                            } else {
+                                control::Reply::ok(control::json_string("reloaded"))
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Config Error: {}", e);
+                            match e.source() {
+                                Some(error_source) => control::Reply::error(format!("{}: {}", e, error_source)),
+                                None => control::Reply::error(e),
+                            }
+                        }
+                    },
+                    control::Request::Pause => {
+                        execution_is_paused = true;
+                        // Hotkeys that are held or waiting on a timer don't fire while paused
+                        last_hotkey = None;
+                        pending_release = false;
+                        long_press = None;
+                        tap_counter = multitap::TapCounter::default();
+                        match set_grabbed(&mut keyboard_stream_map, false) {
+                            Ok(()) => control::Reply::ok(control::json_string("paused")),
+                            Err(e) => control::Reply::error(format!("Paused, but failed to release devices: {}", e)),
+                        }
+                    }
+                    control::Request::Resume => {
+                        execution_is_paused = false;
+                        match set_grabbed(&mut keyboard_stream_map, true) {
+                            Ok(()) => control::Reply::ok(control::json_string("resumed")),
+                            Err(e) => control::Reply::error(format!("Resumed, but failed to grab devices: {}", e)),
+                        }
+                    }
+                    control::Request::Status => control::Reply::ok(format!(
+                        "{{\"paused\":{},\"mode\":{},\"mode_stack\":{},\"devices\":{}}}",
+                        execution_is_paused,
+                        control::json_string(&config.modes[mode_stack[mode_stack.len() - 1]].name),
+                        events::mode_stack_json(&config.modes, &mode_stack),
+                        keyboard_stream_map.len(),
+                    )),
+                    control::Request::ListDevices => {
+                        control::Reply::ok(control::json_array(keyboard_stream_map.iter().map(|(node, stream)| {
+                            format!(
+                                "{{\"path\":{},\"name\":{}}}",
+                                control::json_string(node),
+                                control::json_string(stream.device().name().unwrap_or("[unknown]")),
+                            )
+                        })))
+                    }
+                    control::Request::ShowModeStack => control::Reply::ok(events::mode_stack_json(&config.modes, &mode_stack)),
+                    control::Request::ListProcesses => control::Reply::ok(supervisor.records_json()),
+                };
+                let _ = reply_tx.send(reply);
+            }
+
             Some(Ok(event)) = udev.next() => {
                 if !event.is_initialized() {
                     log::warn!("Received udev event with uninitialized device.");
@@ -418,11 +604,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             },
                             Ok(device) => device
                         };
-                        let name = device.name().unwrap_or("[unknown]").to_string();
-                        if arg_devices.contains(&name) || check_device_is_keyboard(&device) {
-                            log::info!("Device '{}' at '{}' added.", name, node);
-                            let _ = device.grab();
-                            keyboard_states.insert(node.to_string(), KeyboardState::new());
+                        let info = device::DeviceInfo::new(node, &device);
+                        if config.device_rules.should_grab(&device, &info) {
+                            log::info!("Device '{}' at '{}' added.", info.name, node);
+                            // Paused devices are grabbed along with the others on resume
+                            if !execution_is_paused {
+                                let _ = device.grab();
+                            }
+                            keyboard_states.insert(node.to_string(), KeyboardState::new(info, &device));
                             keyboard_stream_map.insert(node.to_string(), device.into_event_stream()?);
                         }
                     }
@@ -440,21 +629,110 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 }
             }
 
-            Some((node, Ok(event))) = keyboard_stream_map.next() => {
-                let keyboard_state = &mut keyboard_states.get_mut(&node).expect("device not in states map");
+            Some((node, Ok(event), replayed)) = next_event(&mut replayed_events, &mut keyboard_stream_map) => {
+                // Paused devices are released, so applications get their input directly. Only
+                // the held keys are kept track of, and releases are passed on for the keys that
+                // were pressed through the uinput device before the pause.
+                if execution_is_paused {
+                    if let (InputEventKind::Key(key), Some(keyboard_state)) = (event.kind(), keyboard_states.get_mut(&node)) {
+                        match event.value() {
+                            1 => match modifiers_map.get(&key) {
+                                Some(modifier) => { keyboard_state.state_modifiers.insert(*modifier); }
+                                None => keyboard_state.state_keysyms.insert(key),
+                            },
+                            0 => {
+                                let key = remapper.apply(key, 0, &config.modes[mode_stack[mode_stack.len() - 1]].remaps);
+                                match modifiers_map.get(&key) {
+                                    Some(modifier) => { keyboard_state.state_modifiers.remove(modifier); }
+                                    None => keyboard_state.state_keysyms.remove(key),
+                                }
+                                effects.output.event(InputEvent::new(evdev::EventType::KEY, key.code(), 0));
+                            }
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
 
                 let key = match event.kind() {
                     InputEventKind::Key(keycode) => keycode,
-                    InputEventKind::Switch(_) => {
+                    InputEventKind::Switch(switch) => {
+                        let trigger = config::Trigger::Switch { switch, on: event.value() == 1 };
+                        let device = &keyboard_states[&node].device;
+                        let hotkey = config.modes[mode_stack[mode_stack.len() - 1]].hotkeys.iter()
+                            .find(|hotkey| hotkey.trigger == trigger && hotkey.matches_device(device))
+                            .cloned();
+                        if let Some(hotkey) = hotkey {
+                            let swallow = !(&hotkey).is_send();
+                            send_command(hotkey, &device.name, &config.modes, &mut mode_stack, &effects).await;
+                            if swallow {
+                                continue;
+                            }
+                        }
                         uinput_switches_device.emit(&[event]).unwrap();
                         continue
                     }
+                    InputEventKind::RelAxis(axis) => {
+                        if let Some(scroll) = config::Scroll::from_event(axis, event.value()) {
+                            modifier_tap = None;
+                            let state_modifiers = held_modifiers(&keyboard_states);
+                            let device = &keyboard_states[&node].device;
+                            let hotkey = config.modes[mode_stack[mode_stack.len() - 1]].hotkeys.iter().find(|hotkey| {
+                                hotkey.trigger == config::Trigger::Scroll(scroll)
+                                && hotkey.matches_device(device)
+                                && hotkey.keybinding.modifiers_match(&state_modifiers)
+                            }).cloned();
+                            if let Some(hotkey) = hotkey {
+                                // High resolution events only add up to the detents of the regular axis
+                                if !config::Scroll::is_hi_res(axis) {
+                                    for _ in 0..event.value().unsigned_abs() {
+                                        send_command(hotkey.clone(), &device.name, &config.modes, &mut mode_stack, &effects).await;
+                                    }
+                                }
+                                if !(&hotkey).is_send() {
+                                    continue;
+                                }
+                            }
+                        }
+                        effects.output.event(event);
+                        continue
+                    }
                     _ => {
-                        uinput_device.emit(&[event]).unwrap();
+                        effects.output.event(event);
                         continue
                     }
                 };
 
+                // Dual-role keys are resolved before remaps, on the physical key
+                if !replayed {
+                    let was_pending = tap_hold.is_pending();
+                    match tap_hold.feed(key, event.value()) {
+                        taphold::Outcome::Pass => {}
+                        taphold::Outcome::Replace(actions) => {
+                            if !was_pending && tap_hold.is_pending() {
+                                tap_hold_node.clone_from(&node);
+                                tap_hold_timer.as_mut().reset(Instant::now() + config.settings.tap_hold_timeout);
+                            }
+                            apply_tap_hold_actions(actions, &node, &mut replayed_events, &config.modes, &mut mode_stack, &effects.event_bus);
+                            continue;
+                        }
+                        taphold::Outcome::Prepend(actions) => {
+                            apply_tap_hold_actions(actions, &tap_hold_node, &mut replayed_events, &config.modes, &mut mode_stack, &effects.event_bus);
+                            replayed_events.push_back((node, event, false));
+                            continue;
+                        }
+                    }
+                }
+
+                // Remaps apply before the key is matched against hotkeys or emitted
+                let key = remapper.apply(key, event.value(), &config.modes[mode_stack[mode_stack.len() - 1]].remaps);
+                let event = InputEvent::new(evdev::EventType::KEY, key.code(), event.value());
+
+                // Replayed events can outlive the device that was unplugged in the meantime
+                let Some(keyboard_state) = keyboard_states.get_mut(&node) else {
+                    continue;
+                };
+
                 match event.value() {
                     // Key press
                     1 => {
@@ -469,18 +747,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     0 => {
                         if last_hotkey.is_some() && pending_release {
                             pending_release = false;
-                            send_command(last_hotkey.clone().unwrap(), &modes, &mut mode_stack, tx.clone()).await;
-                            last_hotkey = None;
+                            let (hotkey, device) = last_hotkey.take().unwrap();
+                            send_command(hotkey, &device, &config.modes, &mut mode_stack, &effects).await;
                         }
                         if let Some(modifier) = modifiers_map.get(&key) {
-                            if let Some(hotkey) = &last_hotkey {
-                                if hotkey.modifiers().contains(modifier) {
+                            if let Some((hotkey, _)) = &last_hotkey {
+                                if hotkey.modifiers().iter().any(|m| m.matches(*modifier)) {
                                     last_hotkey = None;
                                 }
                             }
                             keyboard_state.state_modifiers.remove(modifier);
                         } else if keyboard_state.state_keysyms.contains(key) {
-                            if let Some(hotkey) = &last_hotkey {
+                            if let Some((hotkey, _)) = &last_hotkey {
                                 if key == hotkey.keysym() {
                                     last_hotkey = None;
                                 }
@@ -492,48 +770,198 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     _ => {}
                 }
 
-                let possible_hotkeys: Vec<&config::Hotkey> = modes[mode_stack[mode_stack.len() - 1]].hotkeys.iter()
-                    .filter(|hotkey| hotkey.modifiers().len() == keyboard_state.state_modifiers.len())
-                    .collect();
+                // Mouse buttons combine with the modifiers held on any keyboard, even when the state
+                // is kept per device
+                let global_state = config.settings.input_state == config::InputState::Global;
+                let state_modifiers = if global_state || is_pointer_button(key) {
+                    held_modifiers(&keyboard_states)
+                } else {
+                    keyboard_states[&node].state_modifiers.clone()
+                };
+                let keyboard_state = &keyboard_states[&node];
+                let no_keys_held = if global_state {
+                    keyboard_states.values().all(|state| state.state_keysyms.iter().next().is_none())
+                } else {
+                    keyboard_state.state_keysyms.iter().next().is_none()
+                };
 
-                let event_in_hotkeys = modes[mode_stack[mode_stack.len() - 1]].hotkeys.iter().any(|hotkey| {
-                    hotkey.keysym().code() == event.code() &&
-                        (!keyboard_state.state_modifiers.is_empty() && hotkey.modifiers().contains(&config::Modifier::Any) || keyboard_state.state_modifiers
-                        .iter()
-                        .all(|x| hotkey.modifiers().contains(x)) &&
-                    keyboard_state.state_modifiers.len() == hotkey.modifiers().len())
+                // A modifier is tapped when it's released before any other key is pressed
+                let tapped_modifier = match event.value() {
+                    1 => {
+                        modifier_tap = modifiers_map.get(&key)
+                            .filter(|_| no_keys_held)
+                            .map(|modifier| {
+                                let mut held = state_modifiers.clone();
+                                held.remove(modifier);
+                                (node.clone(), *modifier, held, Instant::now())
+                            });
+                        None
+                    }
+                    // Held for too long it was probably used with the pointer of an ungrabbed mouse
+                    0 => modifier_tap.take()
+                        .filter(|(tap_node, modifier, ..)| (global_state || *tap_node == node) && modifiers_map.get(&key) == Some(modifier))
+                        .filter(|(.., pressed)| config.settings.modifier_tap_timeout.is_none_or(|timeout| pressed.elapsed() <= timeout)),
+                    _ => None,
+                };
+
+                // Pressing another combo ends the counting of taps right away
+                if event.value() == 1 && !modifiers_map.contains_key(&key) && tap_counter.is_pending_other(key, &state_modifiers) {
+                    if let Some((hotkey, device)) = tap_counter.finish() {
+                        send_command(hotkey, &device, &config.modes, &mut mode_stack, &effects).await;
+                    }
+                }
+
+                // Repeats of the combo whose taps are counted would fire its single-tap hotkey
+                if event.value() == 2 && tap_counter.is_pending_combo(key, &state_modifiers) {
+                    continue;
+                }
+
+                // Chords consume every key press that starts, advances or completes them
+                if event.value() == 1 && !modifiers_map.contains_key(&key) {
+                    let hotkeys = &config.modes[mode_stack[mode_stack.len() - 1]].hotkeys;
+                    match chord_matcher.press(key, &state_modifiers, &keyboard_state.device, hotkeys, config.settings.chord_abort_key) {
+                        ChordOutcome::Unmatched => {}
+                        ChordOutcome::Pending => {
+                            if let Some(timeout) = config.settings.chord_timeout {
+                                chord_timer.as_mut().reset(Instant::now() + timeout);
+                            }
+                            continue;
+                        }
+                        ChordOutcome::Aborted => continue,
+                        ChordOutcome::Complete(hotkey) => {
+                            send_command(*hotkey, &keyboard_state.device.name, &config.modes, &mut mode_stack, &effects).await;
+                            continue;
+                        }
+                    }
+                } else if chord_matcher.swallows(key, event.value()) {
+                    continue;
+                }
+
+                // Repeats of the key of a waiting long-press hotkey would fire its tap right away
+                if event.value() == 2 && long_press.as_ref().is_some_and(|(hotkey, ..)| hotkey.keysym() == key) {
+                    continue;
+                }
+
+                // Releasing the key of a waiting long-press hotkey makes it a tap
+                if event.value() == 0 && long_press.as_ref().is_some_and(|(hotkey, ..)| hotkey.keysym() == key) {
+                    let (hotkey, tap, device) = long_press.take().unwrap();
+                    match tap {
+                        Some(tap) => {
+                            send_command(tap, &device, &config.modes, &mut mode_stack, &effects).await;
+                        }
+                        // The press was swallowed, so the tap is passed on as a whole
+                        None if !(&hotkey).is_send() => {
+                            let press = InputEvent::new(evdev::EventType::KEY, key.code(), 1);
+                            effects.output.event(press);
+                            effects.output.event(event);
+                        }
+                        None => effects.output.event(event),
+                    }
+                    continue;
+                }
+
+                let mut possible_hotkeys: Vec<&config::Hotkey> = config.modes[mode_stack[mode_stack.len() - 1]].hotkeys.iter()
+                    .filter(|hotkey| hotkey.chord.is_empty() && hotkey.matches_device(&keyboard_state.device) && hotkey.keybinding.modifiers_match(&state_modifiers))
+                    .collect();
+                // Bindings with sided modifiers take precedence over the generic ones of the same key
+                let sided_keys: Vec<Key> = possible_hotkeys.iter().filter(|hotkey| hotkey.keybinding.is_sided()).map(|hotkey| hotkey.keysym()).collect();
+                possible_hotkeys.retain(|hotkey| hotkey.keybinding.is_sided() || !sided_keys.contains(&hotkey.keysym()));
+
+                let event_in_hotkeys = config.modes[mode_stack[mode_stack.len() - 1]].hotkeys.iter().any(|hotkey| {
+                    hotkey.chord.is_empty()
+                    && hotkey.matches_device(&keyboard_state.device)
+                    && hotkey.keysym().code() == event.code()
+                    && hotkey.keybinding.modifiers_match(&state_modifiers)
                     && !hotkey.is_send()
                         });
 
                 // Only emit event to virtual device when swallow option is off
-                if !modes[mode_stack[mode_stack.len()-1]].options.swallow
+                if !config.modes[mode_stack[mode_stack.len()-1]].options.swallow
                 // Don't emit event to virtual device if it's from a valid hotkey
-                && !event_in_hotkeys {
-                    uinput_device.emit(&[event]).unwrap();
+                && !event_in_hotkeys
+                // Nor if it's from a swallowed user-defined modifier
+                && !config.custom_modifiers.iter().any(|modifier| modifier.swallow && modifier.keys.contains(&key)) {
+                    effects.output.event(event);
+                }
+
+                // Fired once the release of the modifier went out, so that outputs don't see it held
+                if let Some((_, modifier, held, _)) = tapped_modifier {
+                    let hotkey = config.modes[mode_stack[mode_stack.len() - 1]].hotkeys.iter().find(|hotkey| {
+                        matches!(hotkey.trigger, config::Trigger::ModifierTap(bound) if bound.matches(modifier))
+                        && hotkey.matches_device(&keyboard_state.device)
+                        && hotkey.keybinding.modifiers_match(&held)
+                    }).cloned();
+                    if let Some(hotkey) = hotkey {
+                        send_command(hotkey, &keyboard_state.device.name, &config.modes, &mut mode_stack, &effects).await;
+                    }
+                }
+
+                if event.value() == 2 && hotkey_kernel_repeat {
+                    if let Some((hotkey, device)) = last_hotkey.clone().filter(|(hotkey, _)| hotkey.keysym() == key) {
+                        send_command(hotkey, &device, &config.modes, &mut mode_stack, &effects).await;
+                    }
+                    continue;
                 }
 
                 if execution_is_paused || possible_hotkeys.is_empty() || last_hotkey.is_some() {
                     continue;
                 }
 
-                log::debug!("state_modifiers: {:#?}", keyboard_state.state_modifiers);
+                log::debug!("state_modifiers: {:#?}", state_modifiers);
                 log::debug!("state_keysyms: {:#?}", keyboard_state.state_keysyms);
                 log::debug!("hotkey: {:#?}", possible_hotkeys);
 
+                if event.value() == 1 {
+                    let combo: Vec<&config::Hotkey> = possible_hotkeys.iter().copied()
+                        .filter(|hotkey| hotkey.keysym() == key && hotkey.keybinding.modifiers_match(&state_modifiers))
+                        .collect();
+
+                    // Multi-tap hotkeys count the presses of their combo until the window runs out
+                    if combo.iter().any(|hotkey| hotkey.keybinding.taps > 1) {
+                        let hotkeys = combo.into_iter().cloned().collect();
+                        match tap_counter.tap(key, &state_modifiers, hotkeys, &keyboard_state.device.name) {
+                            Some((hotkey, device)) => {
+                                send_command(hotkey, &device, &config.modes, &mut mode_stack, &effects).await;
+                            }
+                            None => multi_tap_timer.as_mut().reset(Instant::now() + config.settings.multi_tap_window),
+                        }
+                        continue;
+                    }
+
+                    // Long-press hotkeys wait for their timer, and the other hotkeys of the same combo
+                    // for the key to be released
+                    if let Some(hotkey) = combo.iter().find(|hotkey| hotkey.keybinding.hold.is_some()) {
+                        let tap = combo.iter().find(|hotkey| hotkey.keybinding.hold.is_none());
+                        long_press_timer.as_mut().reset(Instant::now() + hotkey.keybinding.hold.unwrap());
+                        long_press = Some(((*hotkey).clone(), tap.map(|tap| (*tap).clone()), keyboard_state.device.name.clone()));
+                        continue;
+                    }
+                }
+
                 for hotkey in possible_hotkeys {
                     // this should check if state_modifiers and hotkey.modifiers have the same elements
-                    if (!keyboard_state.state_modifiers.is_empty() && hotkey.modifiers().contains(&config::Modifier::Any) || keyboard_state.state_modifiers.iter().all(|x| hotkey.modifiers().contains(x))
-                        && keyboard_state.state_modifiers.len() == hotkey.modifiers().len())
+                    if hotkey.keybinding.hold.is_none()
+                        && hotkey.keybinding.taps == 1
+                        && hotkey.keybinding.modifiers_match(&state_modifiers)
                         && keyboard_state.state_keysyms.contains(hotkey.keysym())
                     {
-                        last_hotkey = Some(hotkey.clone());
+                        last_hotkey = Some((hotkey.clone(), keyboard_state.device.name.clone()));
+                        hotkey_repeat_interval = None;
                         if pending_release { break; }
                         if hotkey.is_on_release() {
                             pending_release = true;
                             break;
                         }
-                        send_command(hotkey.clone(), &modes, &mut mode_stack, tx.clone()).await;
-                        hotkey_repeat_timer.as_mut().reset(Instant::now() + Duration::from_millis(repeat_cooldown_duration));
+                        send_command(hotkey.clone(), &keyboard_state.device.name, &config.modes, &mut mode_stack, &effects).await;
+                        let mut repeat = hotkey.repeat.or(config.settings.repeat).unwrap_or(default_repeat);
+                        if repeat == config::Repeat::Device {
+                            repeat = keyboard_state.auto_repeat.unwrap_or(default_repeat);
+                        }
+                        hotkey_kernel_repeat = repeat == config::Repeat::Kernel;
+                        if let config::Repeat::Every { delay, interval } = repeat {
+                            hotkey_repeat_timer.as_mut().reset(Instant::now() + delay);
+                            hotkey_repeat_interval = Some(interval);
+                        }
                         continue;
                     }
                 }
@@ -555,6 +983,135 @@ pub fn check_device_is_keyboard(device: &Device) -> bool {
     }
 }
 
+/// Yields the replayed events first and then the events read from the devices. The flag tells
+/// whether the event was produced by a dual-role key, which then leaves it alone.
+async fn next_event(
+    replayed_events: &mut VecDeque<(String, InputEvent, bool)>,
+    keyboard_stream_map: &mut StreamMap<String, EventStream>,
+) -> Option<(String, std::io::Result<InputEvent>, bool)> {
+    match replayed_events.pop_front() {
+        Some((node, event, replayed)) => Some((node, Ok(event), replayed)),
+        None => keyboard_stream_map.next().await.map(|(node, event)| (node, event, false)),
+    }
+}
+
+/// Carries out what dual-role keys resolved to. Key events are replayed as if `node` sent them.
+fn apply_tap_hold_actions(
+    actions: Vec<taphold::Action>,
+    node: &str,
+    replayed_events: &mut VecDeque<(String, InputEvent, bool)>,
+    modes: &[config::Mode],
+    mode_stack: &mut Vec<usize>,
+    event_bus: &events::EventBus,
+) {
+    for action in actions {
+        match action {
+            taphold::Action::Key(key, value) => {
+                let event = InputEvent::new(evdev::EventType::KEY, key.code(), value);
+                replayed_events.push_back((node.to_string(), event, true));
+            }
+            taphold::Action::EnterMode(name) => {
+                if let Some(mode_index) = modes.iter().position(|mode| mode.name == name) {
+                    mode_stack.push(mode_index);
+                    log::info!("Entering mode: {}", name);
+                    event_bus.publish_mode("push", modes, mode_stack);
+                }
+            }
+            taphold::Action::ExitMode(name) => {
+                // Other modes may have been entered on top of it in the meantime
+                let position = mode_stack.iter().rposition(|&index| modes[index].name == name);
+                if let Some(position) = position.filter(|&position| position > 0) {
+                    mode_stack.remove(position);
+                    event_bus.publish_mode("pop", modes, mode_stack);
+                }
+            }
+        }
+    }
+}
+
+/// Switches over to a newly loaded config, starting again from the default mode. Returns the
+/// releases of the keys that held dual-role keys hold down, which still have to be replayed.
+/// Their hold modes are left along with the rest of the mode stack.
+fn apply_config(
+    new: config::Config,
+    config: &mut config::Config,
+    tap_hold: &mut taphold::TapHold,
+    modifiers_map: &mut HashMap<Key, config::Modifier>,
+    mode_stack: &mut Vec<usize>,
+    event_bus: &events::EventBus,
+    supervisor: &supervisor::Supervisor,
+) -> Vec<taphold::Action> {
+    let releases = tap_hold
+        .release_held()
+        .into_iter()
+        .filter(|action| matches!(action, taphold::Action::Key(..)))
+        .collect();
+    // Devices already there were picked at startup, only hotplugged ones see the new rules
+    if new.device_rules != config.device_rules {
+        log::warn!("Device rules changed, restart swhkd to apply them to the current devices.");
+    }
+    *config = new;
+    *tap_hold = taphold::TapHold::new(&config.dual_roles);
+    *modifiers_map = build_modifiers_map(&config.custom_modifiers);
+    supervisor.set_notify_failures(config.settings.notify_failures);
+    *mode_stack = vec![0];
+    event_bus.publish_mode("reset", &config.modes, mode_stack);
+    releases
+}
+
+/// The keys acting as modifiers, the built-in ones along with those declared in the config.
+fn build_modifiers_map(
+    custom_modifiers: &[config::CustomModifier],
+) -> HashMap<Key, config::Modifier> {
+    let mut modifiers_map = HashMap::from([
+        (Key::KEY_LEFTMETA, config::Modifier::LeftSuper),
+        (Key::KEY_RIGHTMETA, config::Modifier::RightSuper),
+        (Key::KEY_LEFTALT, config::Modifier::Alt),
+        (Key::KEY_RIGHTALT, config::Modifier::Altgr),
+        (Key::KEY_LEFTCTRL, config::Modifier::LeftControl),
+        (Key::KEY_RIGHTCTRL, config::Modifier::RightControl),
+        (Key::KEY_LEFTSHIFT, config::Modifier::LeftShift),
+        (Key::KEY_RIGHTSHIFT, config::Modifier::RightShift),
+    ]);
+    for (index, modifier) in custom_modifiers.iter().enumerate() {
+        for key in &modifier.keys {
+            modifiers_map.insert(*key, config::Modifier::Custom(index));
+        }
+    }
+    modifiers_map
+}
+
+/// The modifiers held on any of the grabbed devices.
+fn held_modifiers(keyboard_states: &HashMap<String, KeyboardState>) -> HashSet<config::Modifier> {
+    keyboard_states.values().flat_map(|state| state.state_modifiers.iter().copied()).collect()
+}
+
+/// Whether `key` is one of the buttons of a mouse, as opposed to a keyboard key.
+fn is_pointer_button(key: Key) -> bool {
+    (Key::BTN_LEFT.code()..=Key::BTN_TASK.code()).contains(&key.code())
+}
+
+/// Grabs or releases the devices swhkd reads from. Grabs only belong to the file descriptor
+/// that took them, so this has to go through the streams rather than newly opened devices.
+fn set_grabbed(
+    keyboard_stream_map: &mut StreamMap<String, EventStream>,
+    grabbed: bool,
+) -> Result<(), String> {
+    let mut errors = Vec::new();
+    for (node, stream) in keyboard_stream_map.iter_mut() {
+        let device = stream.device_mut();
+        let result = if grabbed { device.grab() } else { device.ungrab() };
+        if let Err(e) = result {
+            errors.push(format!("{}: {}", node, e));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
 pub fn setup_swhkd(invoking_uid: u32, runtime_path: PathBuf) {
     // Set a sane process umask.
     log::trace!("Setting process umask.");
@@ -610,16 +1167,44 @@ pub fn setup_swhkd(invoking_uid: u32, runtime_path: PathBuf) {
     }
 }
 
+/// Where fired hotkeys take effect, besides the mode stack.
+pub struct Effects {
+    event_bus: events::EventBus,
+    output: output::Writer,
+    /// The executor that runs the commands.
+    jobs: mpsc::Sender<supervisor::Job>,
+}
+
+/// Fires `hotkey`, which was pressed on the device named `device`.
 pub async fn send_command(
     hotkey: Hotkey,
+    device: &str,
     modes: &[config::Mode],
     mode_stack: &mut Vec<usize>,
-    tx: mpsc::Sender<String>,
+    effects: &Effects,
 ) {
+    let Effects { event_bus, output, jobs } = effects;
     log::info!("Hotkey pressed: {:#?}", hotkey);
+    let label = hotkey.label.clone();
+    let mode = modes[hotkey.id.0].name.clone();
+    let policy = hotkey.policy;
+    let shell = hotkey.shell.clone().unwrap_or_default();
+    // The modes the hotkey fired in, not the ones it enters
+    let stack: Vec<&str> = mode_stack.iter().map(|&index| modes[index].name.as_str()).collect();
+    let mut environment = supervisor::Environment {
+        vars: vec![
+            ("SWHKD_MODE".to_string(), stack.last().map(|name| name.to_string())),
+            ("SWHKD_MODE_STACK".to_string(), Some(stack.join(" "))),
+            ("SWHKD_DEVICE".to_string(), Some(device.to_string())),
+        ],
+        dir: None,
+    };
+    environment.extend(&hotkey.environment);
+    output.outputs(hotkey.outputs);
     let mut command = hotkey.command;
     if modes[*mode_stack.last().unwrap()].options.oneoff {
         mode_stack.pop();
+        event_bus.publish_mode("pop", modes, mode_stack);
     }
     for mode in hotkey.mode_instructions.iter() {
         match mode {
@@ -627,18 +1212,27 @@ pub async fn send_command(
                 if let Some(mode_index) = modes.iter().position(|modename| modename.name.eq(name)) {
                     mode_stack.push(mode_index);
                     log::info!("Entering mode: {}", name);
+                    event_bus.publish_mode("push", modes, mode_stack);
                 }
             }
             sweet::ModeInstruction::Escape => {
                 mode_stack.pop();
+                event_bus.publish_mode("pop", modes, mode_stack);
             }
         }
     }
     if command.ends_with(" &&") {
         command = command.strip_suffix(" &&").unwrap().to_string();
     }
+    // Hotkeys that only change modes or emit input have nothing to run
+    if command.trim().is_empty() {
+        return;
+    }
 
-    match tx.send(command).await {
+    match jobs
+        .send(supervisor::Job { hotkey: label, mode, command, policy, shell, environment })
+        .await
+    {
         Ok(_) => {}
         Err(e) => {
             log::error!("Failed to send command: {}", e);
@@ -0,0 +1,248 @@
+use evdev::{BusType, Device, Key, RelativeAxisType};
+use std::str::FromStr;
+
+/// The properties of a device that selectors can match on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub path: String,
+    pub name: String,
+    pub phys: String,
+    pub uniq: String,
+    pub bus: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub classes: Vec<DeviceClass>,
+}
+
+impl DeviceInfo {
+    pub fn new(path: &str, device: &Device) -> Self {
+        let id = device.input_id();
+        DeviceInfo {
+            path: path.to_string(),
+            name: device.name().unwrap_or("[unknown]").to_string(),
+            phys: device.physical_path().unwrap_or_default().to_string(),
+            uniq: device.unique_name().unwrap_or_default().to_string(),
+            bus: id.bus_type().0,
+            vendor: id.vendor(),
+            product: id.product(),
+            classes: DeviceClass::detect(device),
+        }
+    }
+}
+
+/// Rough capability classes of input devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    Keyboard,
+    Mouse,
+    Touchpad,
+    Switch,
+}
+
+impl DeviceClass {
+    fn detect(device: &Device) -> Vec<Self> {
+        let keys = device.supported_keys();
+        let has_key = |key| keys.is_some_and(|keys| keys.contains(key));
+        let mut classes = Vec::new();
+        if has_key(Key::KEY_ENTER) {
+            classes.push(DeviceClass::Keyboard);
+        }
+        if has_key(Key::BTN_LEFT)
+            && device
+                .supported_relative_axes()
+                .is_some_and(|axes| axes.contains(RelativeAxisType::REL_X))
+        {
+            classes.push(DeviceClass::Mouse);
+        }
+        if has_key(Key::BTN_TOOL_FINGER) && device.supported_absolute_axes().is_some() {
+            classes.push(DeviceClass::Touchpad);
+        }
+        if device.supported_switches().is_some_and(|switches| switches.iter().next().is_some()) {
+            classes.push(DeviceClass::Switch);
+        }
+        classes
+    }
+}
+
+impl FromStr for DeviceClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keyboard" => Ok(DeviceClass::Keyboard),
+            "mouse" => Ok(DeviceClass::Mouse),
+            "touchpad" => Ok(DeviceClass::Touchpad),
+            "switch" => Ok(DeviceClass::Switch),
+            _ => Err(format!("Unknown device class: {}", s)),
+        }
+    }
+}
+
+/// Selects devices by one of their properties. Text properties are matched as globs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Name(String),
+    Path(String),
+    Phys(String),
+    Uniq(String),
+    Bus(u16),
+    Id { vendor: u16, product: Option<u16> },
+    Class(DeviceClass),
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        match self {
+            DeviceSelector::Name(pattern) => glob_match(pattern, &device.name),
+            DeviceSelector::Path(pattern) => glob_match(pattern, &device.path),
+            DeviceSelector::Phys(pattern) => glob_match(pattern, &device.phys),
+            DeviceSelector::Uniq(pattern) => glob_match(pattern, &device.uniq),
+            DeviceSelector::Bus(bus) => device.bus == *bus,
+            DeviceSelector::Id { vendor, product } => {
+                device.vendor == *vendor && product.is_none_or(|p| device.product == p)
+            }
+            DeviceSelector::Class(class) => device.classes.contains(class),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (kind, value) =
+            text.split_once(':').ok_or_else(|| format!("Invalid device selector: {}", text))?;
+        let parse_hex = |s: &str| {
+            u16::from_str_radix(s.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("Invalid hex id: {}", s))
+        };
+        match kind {
+            "name" => Ok(DeviceSelector::Name(value.to_string())),
+            "path" => Ok(DeviceSelector::Path(value.to_string())),
+            "phys" => Ok(DeviceSelector::Phys(value.to_string())),
+            "uniq" => Ok(DeviceSelector::Uniq(value.to_string())),
+            "bus" => match BusType::from_str(&format!("BUS_{}", value.to_uppercase())) {
+                Ok(bus) => Ok(DeviceSelector::Bus(bus.0)),
+                Err(_) => parse_hex(value).map(DeviceSelector::Bus),
+            },
+            "id" => match value.split_once(':') {
+                Some((vendor, product)) => Ok(DeviceSelector::Id {
+                    vendor: parse_hex(vendor)?,
+                    product: Some(parse_hex(product)?),
+                }),
+                None => Ok(DeviceSelector::Id { vendor: parse_hex(value)?, product: None }),
+            },
+            "class" => value.parse().map(DeviceSelector::Class),
+            _ => Err(format!("Unknown device selector: {}", kind)),
+        }
+    }
+
+    /// Parses whitespace separated selectors. Values can be double quoted to contain spaces,
+    /// e.g. `name:"Macro Pad" id:1234:abcd`.
+    pub fn parse_list(text: &str) -> Result<Vec<Self>, String> {
+        let mut selectors = Vec::new();
+        let mut token = String::new();
+        let mut quoted = false;
+        for c in text.chars().chain(std::iter::once(' ')) {
+            match c {
+                '"' => quoted = !quoted,
+                c if c.is_whitespace() && !quoted => {
+                    if !token.is_empty() {
+                        selectors.push(Self::parse(&token)?);
+                        token.clear();
+                    }
+                }
+                c => token.push(c),
+            }
+        }
+        if quoted {
+            return Err(format!("Unclosed quote in: {}", text));
+        }
+        if selectors.is_empty() {
+            return Err("Missing device selector".to_string());
+        }
+        Ok(selectors)
+    }
+}
+
+/// Decides which devices get grabbed. Without include rules every keyboard is grabbed, along
+/// with every mouse if `grab_pointers` is set and every switch device if `grab_switches` is.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceRules {
+    pub include: Vec<DeviceSelector>,
+    pub exclude: Vec<DeviceSelector>,
+    pub grab_pointers: bool,
+    pub grab_switches: bool,
+}
+
+impl DeviceRules {
+    pub fn extend(&mut self, other: DeviceRules) {
+        self.include.extend(other.include);
+        self.exclude.extend(other.exclude);
+        self.grab_pointers |= other.grab_pointers;
+        self.grab_switches |= other.grab_switches;
+    }
+
+    pub fn should_grab(&self, device: &Device, info: &DeviceInfo) -> bool {
+        // Never grab our own virtual devices, that would feed every event back to us.
+        if info.name == "swhkd virtual output" || info.name == "swhkd switches virtual output" {
+            return false;
+        }
+
+        let included = if self.include.is_empty() {
+            crate::check_device_is_keyboard(device)
+                || self.grab_pointers && info.classes.contains(&DeviceClass::Mouse)
+                || self.grab_switches && info.classes.contains(&DeviceClass::Switch)
+        } else {
+            self.include.iter().any(|selector| selector.matches(info))
+        };
+        if included && self.exclude.iter().any(|selector| selector.matches(info)) {
+            log::debug!("Device '{}' at '{}' excluded.", info.name, info.path);
+            return false;
+        }
+        included
+    }
+}
+
+/// Matches `text` against a pattern where `*` matches any run of characters and `?` any
+/// single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text position it was tried at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("*Keyboard*", "AT Translated Set 2 Keyboard"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("usb-?", "usb-1"));
+        assert!(glob_match("a*b*c", "axxbyybc"));
+        assert!(!glob_match("usb-?", "usb-12"));
+        assert!(!glob_match("*Mouse", "Mouse Keyboard"));
+        assert!(!glob_match("", "a"));
+    }
+}
@@ -0,0 +1,97 @@
+use crate::{config, control};
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::UnixStream,
+    sync::broadcast::{self, error::RecvError},
+};
+
+/// World readable socket publishing mode changes as newline delimited JSON.
+pub const EVENTS_SOCKET_PATH: &str = "/run/swhkd/events.sock";
+
+/// Fans out mode change events to every subscriber of the events socket.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<String>,
+    // The last published state, sent to new subscribers so they don't have to wait for a change.
+    current: Arc<Mutex<String>>,
+}
+
+impl EventBus {
+    pub fn new(modes: &[config::Mode], mode_stack: &[usize]) -> Self {
+        let (sender, _) = broadcast::channel(64);
+        let current = Arc::new(Mutex::new(mode_event("state", modes, mode_stack)));
+        EventBus { sender, current }
+    }
+
+    /// Publishes a `push`, `pop` or `reset` event carrying the active mode and the full stack.
+    pub fn publish_mode(&self, event: &str, modes: &[config::Mode], mode_stack: &[usize]) {
+        let line = mode_event(event, modes, mode_stack);
+        *self.current.lock().unwrap() = mode_event("state", modes, mode_stack);
+        // Sending only fails when nobody is subscribed.
+        let _ = self.sender.send(line);
+    }
+}
+
+/// Encodes the names of the modes on the mode stack as a JSON array, bottom first.
+pub fn mode_stack_json(modes: &[config::Mode], mode_stack: &[usize]) -> String {
+    control::json_array(mode_stack.iter().map(|&index| control::json_string(&modes[index].name)))
+}
+
+fn mode_event(event: &str, modes: &[config::Mode], mode_stack: &[usize]) -> String {
+    let mode = mode_stack.last().map_or("", |&index| modes[index].name.as_str());
+    format!(
+        "{{\"event\":{},\"mode\":{},\"stack\":{}}}",
+        control::json_string(event),
+        control::json_string(mode),
+        mode_stack_json(modes, mode_stack),
+    )
+}
+
+/// Binds the events socket and streams every published event to each subscriber.
+pub fn listen(path: &str, bus: EventBus) -> io::Result<()> {
+    let path = Path::new(path);
+    let listener = control::bind(path, 0o666)?;
+    log::debug!("Publishing mode events on {}", path.display());
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("Failed to accept events connection: {}", e);
+                    continue;
+                }
+            };
+            let receiver = bus.sender.subscribe();
+            let current = bus.current.lock().unwrap().clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_subscriber(stream, receiver, current).await {
+                    log::debug!("Events subscriber disconnected: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve_subscriber(
+    mut stream: UnixStream,
+    mut receiver: broadcast::Receiver<String>,
+    current: String,
+) -> io::Result<()> {
+    stream.write_all(format!("{}\n", current).as_bytes()).await?;
+    loop {
+        match receiver.recv().await {
+            Ok(line) => stream.write_all(format!("{}\n", line).as_bytes()).await?,
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Events subscriber lagged behind, {} events dropped", skipped);
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
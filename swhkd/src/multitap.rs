@@ -0,0 +1,105 @@
+use crate::config::{Hotkey, Modifier};
+use evdev::Key;
+use std::collections::HashSet;
+
+/// Counts repeated presses of the same combo for multi-tap hotkeys such as `2x super + e`.
+#[derive(Debug, Default)]
+pub struct TapCounter {
+    pending: Option<Taps>,
+}
+
+#[derive(Debug)]
+struct Taps {
+    key: Key,
+    modifiers: HashSet<Modifier>,
+    count: u32,
+    /// Every hotkey of the combo, whatever its number of taps.
+    hotkeys: Vec<Hotkey>,
+    /// The name of the device the combo is pressed on.
+    device: String,
+}
+
+impl TapCounter {
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Whether taps of the combo of `key` with `modifiers` are being counted.
+    pub fn is_pending_combo(&self, key: Key, modifiers: &HashSet<Modifier>) -> bool {
+        self.pending.as_ref().is_some_and(|taps| taps.key == key && taps.modifiers == *modifiers)
+    }
+
+    /// Whether taps of a combo other than `key` with `modifiers` are being counted.
+    pub fn is_pending_other(&self, key: Key, modifiers: &HashSet<Modifier>) -> bool {
+        self.pending.as_ref().is_some_and(|taps| taps.key != key || taps.modifiers != *modifiers)
+    }
+
+    /// Counts a press of the combo that `hotkeys` are bound to on the device named `device`.
+    /// Returns the hotkey to fire along with the device once no hotkey of the combo needs more
+    /// taps, otherwise the caller waits for the next tap.
+    pub fn tap(
+        &mut self,
+        key: Key,
+        modifiers: &HashSet<Modifier>,
+        hotkeys: Vec<Hotkey>,
+        device: &str,
+    ) -> Option<(Hotkey, String)> {
+        let count = match &self.pending {
+            Some(taps) if taps.key == key && taps.modifiers == *modifiers => taps.count + 1,
+            _ => 1,
+        };
+        let most = hotkeys.iter().map(|hotkey| hotkey.keybinding.taps).max().unwrap_or(1);
+        let device = device.to_string();
+        self.pending = Some(Taps { key, modifiers: modifiers.clone(), count, hotkeys, device });
+        if count >= most {
+            return self.finish();
+        }
+        None
+    }
+
+    /// Stops counting and returns the hotkey for the number of taps so far, if there is one,
+    /// along with the name of the device.
+    pub fn finish(&mut self) -> Option<(Hotkey, String)> {
+        let taps = self.pending.take()?;
+        let hotkey =
+            taps.hotkeys.into_iter().find(|hotkey| hotkey.keybinding.taps == taps.count)?;
+        Some((hotkey, taps.device))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hotkey(taps: u32, command: &str) -> Hotkey {
+        let mut hotkey = Hotkey::new(Key::KEY_E, [Modifier::Super], command.to_string());
+        hotkey.keybinding.taps = taps;
+        hotkey
+    }
+
+    #[test]
+    fn fires_once_no_hotkey_needs_more_taps() {
+        let mut counter = TapCounter::default();
+        let modifiers = HashSet::from([Modifier::Super]);
+        let hotkeys = vec![hotkey(1, "one"), hotkey(2, "two")];
+        assert!(counter.tap(Key::KEY_E, &modifiers, hotkeys.clone(), "Keyboard").is_none());
+        assert!(counter.is_pending());
+        assert!(counter.is_pending_combo(Key::KEY_E, &modifiers));
+        assert!(!counter.is_pending_other(Key::KEY_E, &modifiers));
+        assert!(!counter.is_pending_combo(Key::KEY_F, &modifiers));
+        assert!(counter.is_pending_other(Key::KEY_F, &modifiers));
+        let (fired, device) = counter.tap(Key::KEY_E, &modifiers, hotkeys, "Keyboard").unwrap();
+        assert_eq!(fired.command, "two");
+        assert_eq!(device, "Keyboard");
+        assert!(!counter.is_pending());
+    }
+
+    #[test]
+    fn finish_picks_hotkey_of_the_taps_so_far() {
+        let mut counter = TapCounter::default();
+        let modifiers = HashSet::from([Modifier::Super]);
+        counter.tap(Key::KEY_E, &modifiers, vec![hotkey(1, "one"), hotkey(3, "three")], "Pad");
+        assert_eq!(counter.finish().unwrap().0.command, "one");
+        assert!(counter.finish().is_none());
+    }
+}
@@ -0,0 +1,247 @@
+use evdev::{uinput::VirtualDevice, EventType, InputEvent, InputEventKind, Key};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::mpsc,
+    time::{sleep_until, Instant},
+};
+
+/// Synthetic input that a hotkey emits through the uinput device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    /// Presses the keys in order and releases them in reverse, e.g. `@send ctrl+shift+t`.
+    Combo(Vec<Key>),
+    /// Plays the steps one after the other, from `@macro` and `@type`.
+    Macro(Vec<MacroStep>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacroStep {
+    Combo(Vec<Key>),
+    Delay(Duration),
+}
+
+/// Maps the characters that `@type` can type to the keys producing them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    keys: HashMap<char, Vec<Key>>,
+}
+
+impl Layout {
+    pub fn get(&self, c: char) -> Option<&[Key]> {
+        self.keys.get(&c).map(Vec::as_slice)
+    }
+
+    pub fn set(&mut self, c: char, keys: Vec<Key>) {
+        self.keys.insert(c, keys);
+    }
+}
+
+impl Default for Layout {
+    /// The US QWERTY layout.
+    fn default() -> Self {
+        let letters = [
+            Key::KEY_A,
+            Key::KEY_B,
+            Key::KEY_C,
+            Key::KEY_D,
+            Key::KEY_E,
+            Key::KEY_F,
+            Key::KEY_G,
+            Key::KEY_H,
+            Key::KEY_I,
+            Key::KEY_J,
+            Key::KEY_K,
+            Key::KEY_L,
+            Key::KEY_M,
+            Key::KEY_N,
+            Key::KEY_O,
+            Key::KEY_P,
+            Key::KEY_Q,
+            Key::KEY_R,
+            Key::KEY_S,
+            Key::KEY_T,
+            Key::KEY_U,
+            Key::KEY_V,
+            Key::KEY_W,
+            Key::KEY_X,
+            Key::KEY_Y,
+            Key::KEY_Z,
+        ];
+        let unshifted = [
+            ('1', '!', Key::KEY_1),
+            ('2', '@', Key::KEY_2),
+            ('3', '#', Key::KEY_3),
+            ('4', '$', Key::KEY_4),
+            ('5', '%', Key::KEY_5),
+            ('6', '^', Key::KEY_6),
+            ('7', '&', Key::KEY_7),
+            ('8', '*', Key::KEY_8),
+            ('9', '(', Key::KEY_9),
+            ('0', ')', Key::KEY_0),
+            ('-', '_', Key::KEY_MINUS),
+            ('=', '+', Key::KEY_EQUAL),
+            ('[', '{', Key::KEY_LEFTBRACE),
+            (']', '}', Key::KEY_RIGHTBRACE),
+            ('\\', '|', Key::KEY_BACKSLASH),
+            (';', ':', Key::KEY_SEMICOLON),
+            ('\'', '"', Key::KEY_APOSTROPHE),
+            (',', '<', Key::KEY_COMMA),
+            ('.', '>', Key::KEY_DOT),
+            ('/', '?', Key::KEY_SLASH),
+            ('`', '~', Key::KEY_GRAVE),
+        ];
+
+        let mut keys = HashMap::new();
+        for (c, key) in ('a'..='z').zip(letters) {
+            keys.insert(c, vec![key]);
+            keys.insert(c.to_ascii_uppercase(), vec![Key::KEY_LEFTSHIFT, key]);
+        }
+        for (c, shifted, key) in unshifted {
+            keys.insert(c, vec![key]);
+            keys.insert(shifted, vec![Key::KEY_LEFTSHIFT, key]);
+        }
+        keys.insert(' ', vec![Key::KEY_SPACE]);
+        keys.insert('\n', vec![Key::KEY_ENTER]);
+        keys.insert('\t', vec![Key::KEY_TAB]);
+        Layout { keys }
+    }
+}
+
+/// What the output task writes to the uinput device.
+#[derive(Debug)]
+enum Write {
+    /// An event passed through from a grabbed device.
+    Event(InputEvent),
+    /// The outputs of a hotkey.
+    Outputs(Vec<Output>),
+}
+
+/// The keys that applications see as modifiers, lifted while synthetic combos are played.
+const MODIFIER_KEYS: [Key; 8] = [
+    Key::KEY_LEFTMETA,
+    Key::KEY_RIGHTMETA,
+    Key::KEY_LEFTALT,
+    Key::KEY_RIGHTALT,
+    Key::KEY_LEFTCTRL,
+    Key::KEY_RIGHTCTRL,
+    Key::KEY_LEFTSHIFT,
+    Key::KEY_RIGHTSHIFT,
+];
+
+/// Hands writes over to the task that owns the uinput device, so that macros play without
+/// holding back the handling of input.
+#[derive(Debug, Clone)]
+pub struct Writer {
+    tx: mpsc::UnboundedSender<Write>,
+}
+
+impl Writer {
+    /// Moves the device into a task that writes everything it is sent in order. Macro delays
+    /// only hold back the outputs after them, events passed through in the meantime go out
+    /// right away.
+    pub fn spawn(device: VirtualDevice) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(Player { device, held: HashSet::new() }.run(rx));
+        Writer { tx }
+    }
+
+    pub fn event(&self, event: InputEvent) {
+        let _ = self.tx.send(Write::Event(event));
+    }
+
+    /// Plays the outputs of a hotkey once the outputs sent before are done.
+    pub fn outputs(&self, outputs: Vec<Output>) {
+        if !outputs.is_empty() {
+            let _ = self.tx.send(Write::Outputs(outputs));
+        }
+    }
+}
+
+struct Player {
+    device: VirtualDevice,
+    /// The modifier keys that are down on the device.
+    held: HashSet<Key>,
+}
+
+impl Player {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Write>) {
+        let mut steps: VecDeque<MacroStep> = VecDeque::new();
+        // When the delay that holds back the remaining steps is over
+        let mut resume: Option<Instant> = None;
+        loop {
+            if resume.is_none() {
+                while let Some(step) = steps.pop_front() {
+                    match step {
+                        MacroStep::Combo(keys) => log_error(self.combo(&keys)),
+                        MacroStep::Delay(delay) => {
+                            resume = Some(Instant::now() + delay);
+                            break;
+                        }
+                    }
+                }
+            }
+            select! {
+                write = rx.recv() => match write {
+                    Some(Write::Event(event)) => log_error(self.event(event)),
+                    Some(Write::Outputs(outputs)) => {
+                        steps.extend(outputs.into_iter().flat_map(|output| match output {
+                            Output::Combo(keys) => vec![MacroStep::Combo(keys)],
+                            Output::Macro(steps) => steps,
+                        }));
+                    }
+                    None => return,
+                },
+                _ = sleep_until(resume.unwrap_or_else(Instant::now)), if resume.is_some() => {
+                    resume = None;
+                }
+            }
+        }
+    }
+
+    fn event(&mut self, event: InputEvent) -> io::Result<()> {
+        if let InputEventKind::Key(key) = event.kind() {
+            if MODIFIER_KEYS.contains(&key) {
+                match event.value() {
+                    0 => self.held.remove(&key),
+                    _ => self.held.insert(key),
+                };
+            }
+        }
+        self.device.emit(&[event])
+    }
+
+    /// Presses the keys in order and releases them in reverse. The modifier keys that are held
+    /// down are released first and pressed again afterwards, so that they don't mix with the
+    /// combo.
+    fn combo(&mut self, keys: &[Key]) -> io::Result<()> {
+        let held: Vec<Key> = self.held.iter().copied().collect();
+        for key in &held {
+            self.key(*key, 0)?;
+        }
+        for key in keys {
+            self.key(*key, 1)?;
+        }
+        for key in keys.iter().rev() {
+            self.key(*key, 0)?;
+        }
+        for key in &held {
+            self.key(*key, 1)?;
+        }
+        Ok(())
+    }
+
+    fn key(&mut self, key: Key, value: i32) -> io::Result<()> {
+        self.device.emit(&[InputEvent::new(EventType::KEY, key.code(), value)])
+    }
+}
+
+fn log_error(result: io::Result<()>) {
+    if let Err(e) = result {
+        log::error!("Failed to write to the uinput device: {}", e);
+    }
+}
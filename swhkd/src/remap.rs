@@ -0,0 +1,44 @@
+use evdev::Key;
+use std::collections::HashMap;
+
+/// Rewrites keys according to the `remap` statements of the current mode.
+#[derive(Debug, Default)]
+pub struct Remapper {
+    /// The key each held physical key was remapped to when it was pressed. Releases and
+    /// repeats use it even if the mode changed in the meantime, so that no key gets stuck.
+    pressed: HashMap<Key, Key>,
+}
+
+impl Remapper {
+    /// Returns the key that the event with the given `value` stands for.
+    pub fn apply(&mut self, key: Key, value: i32, remaps: &HashMap<Key, Key>) -> Key {
+        match value {
+            1 => {
+                let remapped = remaps.get(&key).copied().unwrap_or(key);
+                self.pressed.insert(key, remapped);
+                remapped
+            }
+            0 => self.pressed.remove(&key).unwrap_or(key),
+            _ => self.pressed.get(&key).copied().unwrap_or(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_keys_keep_their_remap_across_mode_changes() {
+        let mut remapper = Remapper::default();
+        let normal = HashMap::from([(Key::KEY_CAPSLOCK, Key::KEY_ESC)]);
+        let other = HashMap::new();
+        assert_eq!(remapper.apply(Key::KEY_CAPSLOCK, 1, &normal), Key::KEY_ESC);
+        assert_eq!(remapper.apply(Key::KEY_CAPSLOCK, 2, &other), Key::KEY_ESC);
+        assert_eq!(remapper.apply(Key::KEY_CAPSLOCK, 0, &other), Key::KEY_ESC);
+        // The next press follows the mode it happens in
+        assert_eq!(remapper.apply(Key::KEY_CAPSLOCK, 1, &other), Key::KEY_CAPSLOCK);
+        assert_eq!(remapper.apply(Key::KEY_CAPSLOCK, 0, &normal), Key::KEY_CAPSLOCK);
+        assert_eq!(remapper.apply(Key::KEY_A, 1, &normal), Key::KEY_A);
+    }
+}
@@ -0,0 +1,458 @@
+use crate::control;
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
+use std::{
+    collections::HashMap,
+    os::unix::process::CommandExt,
+    process::{Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// A command the main loop hands over to the executor, along with the hotkey that fired it.
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// The label of the hotkey, for logs and replies.
+    pub hotkey: String,
+    /// The mode the hotkey belongs to.
+    pub mode: String,
+    pub command: String,
+    pub policy: Policy,
+    pub shell: Shell,
+    pub environment: Environment,
+}
+
+impl Job {
+    /// What the commands of the job are recorded by. Unlike the index of the hotkey, this stays
+    /// the same across reloads, and the command tells apart hotkeys with the same keys.
+    fn record_key(&self) -> RecordKey {
+        RecordKey {
+            mode: self.mode.clone(),
+            hotkey: self.hotkey.clone(),
+            command: self.command.clone(),
+        }
+    }
+
+    /// The process that runs the command, either through the shell or directly.
+    pub fn command(&self) -> Result<Command, String> {
+        let argv = match &self.shell {
+            Shell::Program(program) => {
+                program.iter().cloned().chain([self.command.clone()]).collect::<Vec<_>>()
+            }
+            Shell::Exec => split_args(&self.command)?,
+        };
+        let (program, args) = argv.split_first().ok_or("Empty command")?;
+        let mut command = Command::new(program);
+        command.args(args);
+        Ok(command)
+    }
+}
+
+/// Changes to the environment of a command, on top of the one fetched from swhks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environment {
+    /// Variables to set, or to unset when there's no value, applied in order.
+    pub vars: Vec<(String, Option<String>)>,
+    /// The working directory. A leading `~` stands for the home directory.
+    pub dir: Option<String>,
+}
+
+impl Environment {
+    /// Applies `other` on top, so that its variables and directory win.
+    pub fn extend(&mut self, other: &Environment) {
+        self.vars.extend(other.vars.iter().cloned());
+        if other.dir.is_some() {
+            self.dir.clone_from(&other.dir);
+        }
+    }
+
+    pub fn apply(&self, command: &mut Command, home: Option<&str>) {
+        for (name, value) in &self.vars {
+            match value {
+                Some(value) => command.env(name, value),
+                None => command.env_remove(name),
+            };
+        }
+        if let Some(dir) = &self.dir {
+            match (dir.strip_prefix('~'), home) {
+                (Some(rest), Some(home)) => command.current_dir(format!("{}{}", home, rest)),
+                _ => command.current_dir(dir),
+            };
+        }
+    }
+}
+
+/// How the command of a hotkey is run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shell {
+    /// Through a shell, with the command as its last argument, e.g. `sh -c <command>`.
+    Program(Vec<String>),
+    /// Split into arguments the way a shell would, and run without one.
+    Exec,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Shell::Program(vec!["sh".to_string(), "-c".to_string()])
+    }
+}
+
+impl Shell {
+    /// Parses `exec`, or a shell with its arguments, `-c` being added when there are none.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut args = split_args(text)?;
+        match args.as_slice() {
+            [] => Err("Missing shell".to_string()),
+            [exec] if exec == "exec" => Ok(Shell::Exec),
+            [_] => {
+                args.push("-c".to_string());
+                Ok(Shell::Program(args))
+            }
+            _ => Ok(Shell::Program(args)),
+        }
+    }
+}
+
+/// Splits a command into arguments at whitespace. Single quotes keep everything literal,
+/// double quotes and backslashes work like in sh, without any expansion.
+pub fn split_args(text: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(arg.take()),
+            '\\' => {
+                let c = chars.next().ok_or("Trailing backslash")?;
+                arg.get_or_insert_with(String::new).push(c);
+            }
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or("Unclosed single quote")? {
+                        '\'' => break,
+                        c => arg.push(c),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next().ok_or("Unclosed double quote")? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or("Unclosed double quote")? {
+                            c @ ('"' | '\\' | '$' | '`') => arg.push(c),
+                            c => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                        },
+                        c => arg.push(c),
+                    }
+                }
+            }
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// What firing a hotkey does while the command it started before is still running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// Starts another instance of the command.
+    #[default]
+    Parallel,
+    /// Does nothing, e.g. for menus that shouldn't open twice.
+    Single,
+    /// Stops the running instances instead, e.g. for scratchpads and recorders.
+    Toggle,
+}
+
+/// The mode, the label and the command of a hotkey, which its record is kept by.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct RecordKey {
+    mode: String,
+    hotkey: String,
+    command: String,
+}
+
+/// What is known about the commands a hotkey started.
+#[derive(Debug, Clone, Default)]
+pub struct Record {
+    pub runs: u64,
+    pub failures: u64,
+    /// Process ids of the commands that haven't exited yet. They lead their own process group.
+    pub running: Vec<u32>,
+    /// Running commands that were stopped by a toggle, so their exit is no failure.
+    pub stopped: Vec<u32>,
+    pub last_status: Option<ExitStatus>,
+    pub last_duration: Option<Duration>,
+}
+
+/// The user that commands run as, along with the environment of their session fetched from
+/// swhks.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub uid: u32,
+    pub gid: u32,
+    pub env: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Session {
+    /// Makes `command` run as the user in their session, with `environment` on top.
+    pub fn apply(&self, command: &mut Command, environment: &Environment) {
+        command.uid(self.uid).gid(self.gid);
+        let env = self.env.lock().unwrap();
+        command.envs(env.iter());
+        environment.apply(command, env.get("HOME").map(String::as_str));
+    }
+}
+
+/// Spawns the commands of hotkeys and waits for them, so that they are reaped and their exit
+/// status and run time are recorded per hotkey.
+#[derive(Debug, Clone, Default)]
+pub struct Supervisor {
+    records: Arc<Mutex<HashMap<RecordKey, Record>>>,
+    notify_failures: Arc<AtomicBool>,
+    session: Session,
+}
+
+impl Supervisor {
+    pub fn new(session: Session) -> Self {
+        Supervisor { session, ..Default::default() }
+    }
+
+    pub fn set_notify_failures(&self, notify: bool) {
+        self.notify_failures.store(notify, Ordering::Relaxed);
+    }
+
+    /// Spawns `command` for the hotkey of `job` in the session of the user and watches it until
+    /// it exits, unless the policy of the job says otherwise.
+    pub fn spawn(&self, job: &Job, mut command: Command) {
+        let running: Vec<u32> = match self.records.lock().unwrap().get(&job.record_key()) {
+            Some(record) => {
+                record.running.iter().filter(|pid| !record.stopped.contains(pid)).copied().collect()
+            }
+            None => vec![],
+        };
+        if !running.is_empty() {
+            match job.policy {
+                Policy::Parallel => {}
+                Policy::Single => {
+                    log::info!("Command of hotkey {} is still running.", job.hotkey);
+                    return;
+                }
+                Policy::Toggle => {
+                    log::info!("Stopping the command of hotkey {}.", job.hotkey);
+                    self.update(job, |record| record.stopped.extend(&running));
+                    for pid in running {
+                        // The whole group, as the shell may have forked the actual program
+                        if let Err(e) = killpg(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+                            log::error!("Failed to stop process {}: {}", pid, e);
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+
+        self.session.apply(&mut command, &job.environment);
+        command.process_group(0);
+        let mut command = tokio::process::Command::from(command);
+        let started = Instant::now();
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                self.fail(
+                    job,
+                    format!("Failed to execute command of hotkey {}: {}", job.hotkey, e),
+                );
+                return;
+            }
+        };
+        let pid = child.id().unwrap_or_default();
+        log::info!("Command of hotkey {} started as process {}.", job.hotkey, pid);
+        self.update(job, |record| {
+            record.runs += 1;
+            record.running.push(pid);
+        });
+
+        let supervisor = self.clone();
+        let job = job.clone();
+        tokio::spawn(async move {
+            let status = child.wait().await;
+            let duration = started.elapsed();
+            let mut stopped = false;
+            supervisor.update(&job, |record| {
+                record.running.retain(|running| *running != pid);
+                stopped = record.stopped.contains(&pid);
+                record.stopped.retain(|running| *running != pid);
+                record.last_duration = Some(duration);
+                record.last_status = status.as_ref().ok().copied();
+            });
+            match status {
+                Ok(status) if status.success() || stopped => {
+                    log::info!("Command of hotkey {} exited after {:?}.", job.hotkey, duration);
+                }
+                Ok(status) => supervisor.fail(
+                    &job,
+                    format!(
+                        "Command of hotkey {} failed with {} after {:?}",
+                        job.hotkey, status, duration
+                    ),
+                ),
+                Err(e) => supervisor.fail(
+                    &job,
+                    format!("Failed to wait for command of hotkey {}: {}", job.hotkey, e),
+                ),
+            }
+        });
+    }
+
+    /// The records of every hotkey that started a command, as a JSON array.
+    pub fn records_json(&self) -> String {
+        let records = self.records.lock().unwrap();
+        let mut keys: Vec<&RecordKey> = records.keys().collect();
+        keys.sort();
+        control::json_array(keys.into_iter().map(|key| {
+            let record = &records[key];
+            let status = record.last_status.map(|status| match status.code() {
+                Some(code) => code.to_string(),
+                None => control::json_string(&status.to_string()),
+            });
+            format!(
+                "{{\"hotkey\":{},\"mode\":{},\"command\":{},\"runs\":{},\"failures\":{},\"running\":{},\"last_status\":{},\"last_duration_ms\":{}}}",
+                control::json_string(&key.hotkey),
+                control::json_string(&key.mode),
+                control::json_string(&key.command),
+                record.runs,
+                record.failures,
+                record.running.len(),
+                status.unwrap_or_else(|| "null".to_string()),
+                record.last_duration.map_or("null".to_string(), |d| d.as_millis().to_string()),
+            )
+        }))
+    }
+
+    fn update(&self, job: &Job, f: impl FnOnce(&mut Record)) {
+        let mut records = self.records.lock().unwrap();
+        f(records.entry(job.record_key()).or_default());
+    }
+
+    /// Logs and records a failure of the command of the hotkey of `job`, notifying if that's
+    /// enabled.
+    pub fn fail(&self, job: &Job, message: String) {
+        log::error!("{}", message);
+        self.update(job, |record| record.failures += 1);
+        if self.notify_failures.load(Ordering::Relaxed) {
+            // Sent to the session of the user like their commands, the daemon has none
+            let mut notify = Command::new("notify-send");
+            notify.arg("swhkd").arg(message);
+            self.session.apply(&mut notify, &Environment::default());
+            match tokio::process::Command::from(notify).spawn() {
+                // Waited for like the commands of hotkeys, so that it's reaped
+                Ok(mut child) => {
+                    tokio::spawn(async move { child.wait().await });
+                }
+                Err(e) => log::error!("Failed to run notify-send: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::{getgid, getuid};
+    use std::{ffi::OsStr, path::Path};
+
+    #[test]
+    fn split_args_quotes_like_sh() {
+        assert_eq!(split_args("  ls   -la ").unwrap(), ["ls", "-la"]);
+        assert_eq!(
+            split_args(r#"echo 'a "b"' "c \"d\" \$e \x""#).unwrap(),
+            ["echo", r#"a "b""#, r#"c "d" $e \x"#]
+        );
+        assert_eq!(split_args(r"a\ b ''").unwrap(), ["a b", ""]);
+        assert!(split_args("echo 'a").is_err());
+        assert!(split_args("echo \"a").is_err());
+        assert!(split_args("echo a\\").is_err());
+    }
+
+    #[test]
+    fn shell_adds_the_command_flag() {
+        assert_eq!(Shell::parse("bash").unwrap(), Shell::Program(vec!["bash".into(), "-c".into()]));
+        assert_eq!(
+            Shell::parse("fish -c").unwrap(),
+            Shell::Program(vec!["fish".into(), "-c".into()])
+        );
+        assert_eq!(Shell::parse("exec").unwrap(), Shell::Exec);
+        assert!(Shell::parse("").is_err());
+    }
+
+    #[test]
+    fn environment_expands_the_home_directory() {
+        let environment = Environment {
+            vars: vec![("EDITOR".into(), Some("vi".into())), ("PAGER".into(), None)],
+            dir: Some("~/src".into()),
+        };
+        let mut command = Command::new("true");
+        environment.apply(&mut command, Some("/home/user"));
+        assert_eq!(command.get_current_dir(), Some(Path::new("/home/user/src")));
+        let envs: Vec<_> = command.get_envs().collect();
+        assert_eq!(
+            envs,
+            [(OsStr::new("EDITOR"), Some(OsStr::new("vi"))), (OsStr::new("PAGER"), None)]
+        );
+
+        // Without a home directory the path is kept as written
+        let mut command = Command::new("true");
+        environment.apply(&mut command, None);
+        assert_eq!(command.get_current_dir(), Some(Path::new("~/src")));
+    }
+
+    fn job(hotkey: &str, command: &str, policy: Policy) -> Job {
+        Job {
+            hotkey: hotkey.to_string(),
+            mode: "normal".to_string(),
+            command: command.to_string(),
+            policy,
+            shell: Shell::default(),
+            environment: Environment::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn records_survive_reloads() {
+        let supervisor = Supervisor::new(Session {
+            uid: getuid().as_raw(),
+            gid: getgid().as_raw(),
+            ..Default::default()
+        });
+        let menu = job("super + KEY_D", "sleep 10", Policy::Single);
+        supervisor.spawn(&menu, menu.command().unwrap());
+
+        // A reload that adds a binding before the menu moves both to other indices, the menu
+        // is still known to be running and the new binding is tracked on its own
+        let recorder = job("super + KEY_R", "sleep 10", Policy::Toggle);
+        supervisor.spawn(&recorder, recorder.command().unwrap());
+        supervisor.spawn(&menu, menu.command().unwrap());
+
+        let records = supervisor.records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[&menu.record_key()].runs, 1);
+        assert_eq!(records[&menu.record_key()].running.len(), 1);
+        assert_eq!(records[&recorder.record_key()].running.len(), 1);
+        for pid in records.values().flat_map(|record| &record.running) {
+            killpg(Pid::from_raw(*pid as i32), Signal::SIGTERM).unwrap();
+        }
+    }
+}
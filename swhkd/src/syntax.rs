@@ -0,0 +1,1087 @@
+//! Parsing of the swhkd specific config syntax that sweet doesn't understand.
+//!
+//! The statements handled here are taken out of the config before it is handed over to sweet.
+//! Included files are read here as well, so that they go through the same steps as the main
+//! file instead of reaching sweet with statements it doesn't know.
+//!
+//! This lives here rather than in sweet's grammar because sweet is versioned in its own
+//! repository, so every addition to the syntax would have to wait for a sweet release. Key and
+//! modifier names are still sweet's, `parse_key` and `parse_modifier` hand them over to sweet
+//! and only add the names that swhkd knows on its own.
+
+use crate::config::{
+    ConfigError, CustomModifier, Hotkey, InputState, KeyBinding, Modifier, Repeat, Scroll,
+    Settings, Trigger,
+};
+use crate::device::{DeviceRules, DeviceSelector};
+use crate::output::{Layout, MacroStep, Output};
+use crate::supervisor::{self, Environment, Policy, Shell};
+use crate::taphold::{DualRole, Hold};
+use evdev::{Key, SwitchType};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use sweet::{Definition, KeyAttribute, ModeInstruction, ParserInput, SwhkdParser};
+
+#[derive(Debug, Default)]
+pub struct Extracted {
+    /// Every config file with its extracted lines blanked out, in the order they were given.
+    pub sources: Vec<String>,
+    /// Hotkeys along with the name of the mode block they were declared in.
+    pub hotkeys: Vec<(Option<String>, Hotkey)>,
+    /// Devices that the bindings of a mode are limited to, by mode name.
+    pub mode_devices: Vec<(String, Vec<DeviceSelector>)>,
+    /// Repeat policies of the bindings of a mode, by mode name.
+    pub mode_repeats: Vec<(String, Repeat)>,
+    pub device_rules: DeviceRules,
+    pub settings: Settings,
+    /// `remap <from> <to>` statements along with the name of their mode block.
+    pub remaps: Vec<(Option<String>, Key, Key)>,
+    pub dual_roles: Vec<DualRole>,
+    pub custom_modifiers: Vec<CustomModifier>,
+    /// `env`, `unset` and `cwd` statements along with the name of their mode block.
+    pub environments: Vec<(Option<String>, Environment)>,
+}
+
+/// Internal commands that only swhkd understands. Bindings using them are parsed here.
+const DIRECTIVES: &[&str] = &[
+    "@device", "@send", "@type", "@macro", "@repeat", "@hold", "@single", "@toggle", "@shell",
+    "@exec", "@env", "@unset", "@cwd",
+];
+
+/// The files included by a config with `include <path>` statements.
+pub fn includes(contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .filter_map(|line| line.strip_prefix("include "))
+        .map(|path| PathBuf::from(path.trim()))
+        .collect()
+}
+
+/// Takes the statements that sweet doesn't know out of the given config files, which are the
+/// main file followed by the ones it includes. Later files override the settings of earlier ones.
+pub fn extract(files: &[(PathBuf, String)]) -> Result<Extracted, ConfigError> {
+    let mut extracted = Extracted::default();
+
+    // The layout and the modifiers have to be known before any binding is parsed, wherever
+    // they are declared
+    for (path, contents) in files {
+        for (index, line) in contents.lines().enumerate() {
+            let result = if let Some(mapping) = line.strip_prefix("layout ") {
+                parse_layout(&mut extracted.settings.layout, mapping)
+            } else if let Some(declaration) = line.strip_prefix("modifier ") {
+                parse_custom_modifier(&extracted.custom_modifiers, declaration)
+                    .map(|modifier| extracted.custom_modifiers.push(modifier))
+            } else {
+                Ok(())
+            };
+            result.map_err(|e| in_file(syntax_error(index + 1, e), path))?;
+        }
+    }
+
+    for (path, contents) in files {
+        let source = extract_file(&mut extracted, contents).map_err(|e| in_file(e, path))?;
+        extracted.sources.push(source);
+    }
+    Ok(extracted)
+}
+
+/// Extracts the statements of a single file, returning what is left for sweet.
+fn extract_file(extracted: &mut Extracted, contents: &str) -> Result<String, ConfigError> {
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut kept = lines.clone();
+    let mut mode: Option<String> = None;
+
+    let mut i = 0;
+    while i < lines.len() {
+        let start = i;
+        let (line, next) = logical_line(&lines, i);
+        i = next;
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || line.starts_with(char::is_whitespace) {
+            continue;
+        }
+
+        let line_number = start + 1;
+        let mut words = trimmed.split_whitespace();
+        match words.next() {
+            Some("mode") => {
+                mode = words.next().map(str::to_string);
+                continue;
+            }
+            Some("endmode") => {
+                mode = None;
+                continue;
+            }
+            Some("set") => parse_setting(extracted, words, line_number)?,
+            // Included files are read by the caller
+            Some("layout") | Some("modifier") | Some("include") => {}
+            Some("repeat") => {
+                let repeat = parse_repeat(&words.collect::<Vec<_>>().join(" "))
+                    .map_err(|e| syntax_error(line_number, e))?;
+                match &mode {
+                    Some(mode) => extracted.mode_repeats.push((mode.clone(), repeat)),
+                    None => extracted.settings.repeat = Some(repeat),
+                }
+            }
+            Some("device")
+                if trimmed.starts_with("device include ")
+                    || trimmed.starts_with("device exclude ") =>
+            {
+                if mode.is_some() {
+                    return Err(syntax_error(
+                        line_number,
+                        "Device rules can't be declared in a mode",
+                    ));
+                }
+                let rules = match words.next() {
+                    Some("include") => &mut extracted.device_rules.include,
+                    _ => &mut extracted.device_rules.exclude,
+                };
+                let selectors = DeviceSelector::parse_list(&words.collect::<Vec<_>>().join(" "))
+                    .map_err(|e| syntax_error(line_number, e))?;
+                rules.extend(selectors);
+            }
+            Some(kind @ ("env" | "unset" | "cwd")) => {
+                let text = words.collect::<Vec<_>>().join(" ");
+                let environment =
+                    parse_environment(kind, &text).map_err(|e| syntax_error(line_number, e))?;
+                extracted.environments.push((mode.clone(), environment));
+            }
+            Some("remap") => {
+                let keys = words
+                    .map(|name| parse_key(name).ok_or_else(|| format!("Unknown key: {}", name)))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| syntax_error(line_number, e))?;
+                let [from, to] = keys[..] else {
+                    return Err(syntax_error(line_number, "Expected `remap <from> <to>`"));
+                };
+                extracted.remaps.push((mode.clone(), from, to));
+            }
+            Some("tap_hold") => {
+                if mode.is_some() {
+                    return Err(syntax_error(
+                        line_number,
+                        "Dual-role keys can't be declared in a mode",
+                    ));
+                }
+                let role = parse_dual_role(words).map_err(|e| syntax_error(line_number, e))?;
+                extracted.dual_roles.push(role);
+            }
+            Some("switch") => {
+                let (command, next) = command_block(&lines, i);
+                i = next;
+                let mut hotkey = parse_switch(words).map_err(|e| syntax_error(line_number, e))?;
+                parse_command(&mut hotkey, &command, &extracted.settings.layout)
+                    .map_err(|e| syntax_error(line_number, e))?;
+                // Switch devices aren't grabbed unless something is bound to them
+                extracted.device_rules.grab_switches = true;
+                extracted.hotkeys.push((mode.clone(), hotkey));
+            }
+            Some("device") => {
+                let Some(mode) = &mode else {
+                    return Err(syntax_error(
+                        line_number,
+                        "`device` can only scope mode blocks, use `@device` for single bindings",
+                    ));
+                };
+                let selectors = DeviceSelector::parse_list(&words.collect::<Vec<_>>().join(" "))
+                    .map_err(|e| syntax_error(line_number, e))?;
+                extracted.mode_devices.push((mode.clone(), selectors));
+            }
+            _ => {
+                let (command, next) = command_block(&lines, i);
+                if split_chord(trimmed).len() == 1
+                    && strip_taps(trimmed).is_none()
+                    && !has_extended_key(trimmed)
+                    && !has_modifier_tap(trimmed)
+                    && !has_custom_modifier(trimmed, &extracted.custom_modifiers)
+                    && !has_directive(&command)
+                {
+                    continue;
+                }
+                i = next;
+                let layout = &extracted.settings.layout;
+                let custom = &extracted.custom_modifiers;
+                for hotkey in parse_bindings(trimmed, &command, layout, custom, line_number)? {
+                    extracted.hotkeys.push((mode.clone(), hotkey));
+                }
+            }
+        }
+
+        kept[start..i].iter_mut().for_each(|line| *line = "");
+    }
+
+    Ok(kept.join("\n"))
+}
+
+fn syntax_error(line: usize, message: impl Into<String>) -> ConfigError {
+    ConfigError::Syntax { path: PathBuf::new(), line, message: message.into() }
+}
+
+/// Adds the file a syntax error was found in.
+fn in_file(error: ConfigError, path: &Path) -> ConfigError {
+    match error {
+        ConfigError::Syntax { line, message, .. } => {
+            ConfigError::Syntax { path: path.to_path_buf(), line, message }
+        }
+        error => error,
+    }
+}
+
+/// Joins a line with the following ones as long as it ends with a backslash.
+fn logical_line(lines: &[&str], start: usize) -> (String, usize) {
+    let mut text = String::new();
+    let mut i = start;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        match line.strip_suffix('\\') {
+            Some(line) => text.push_str(line),
+            None => {
+                text.push_str(line);
+                break;
+            }
+        }
+    }
+    (text, i)
+}
+
+/// Collects the indented command lines following a binding, skipping comments.
+fn command_block(lines: &[&str], start: usize) -> (String, usize) {
+    let mut command = String::new();
+    let mut i = start;
+    while i < lines.len() && lines[i].starts_with(char::is_whitespace) {
+        let (line, next) = logical_line(lines, i);
+        i = next;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !command.is_empty() {
+            command.push(' ');
+        }
+        command.push_str(line);
+    }
+    (command, i)
+}
+
+fn parse_setting<'a>(
+    extracted: &mut Extracted,
+    mut words: impl Iterator<Item = &'a str>,
+    line: usize,
+) -> Result<(), ConfigError> {
+    let name = words.next().ok_or_else(|| syntax_error(line, "Missing setting name"))?;
+    let value = words.collect::<Vec<_>>().join(" ");
+    let settings = &mut extracted.settings;
+    match name {
+        "chord_timeout" => {
+            let millis = parse_millis(&value, line)?;
+            settings.chord_timeout = (millis > 0).then(|| Duration::from_millis(millis));
+        }
+        "chord_abort_key" => {
+            settings.chord_abort_key = parse_key(&value)
+                .ok_or_else(|| syntax_error(line, format!("Unknown key: {}", value)))?;
+        }
+        "multi_tap_window" => {
+            settings.multi_tap_window = Duration::from_millis(parse_millis(&value, line)?);
+        }
+        "modifier_tap_timeout" => {
+            let millis = parse_millis(&value, line)?;
+            settings.modifier_tap_timeout = (millis > 0).then(|| Duration::from_millis(millis));
+        }
+        "tap_hold_timeout" => {
+            settings.tap_hold_timeout = Duration::from_millis(parse_millis(&value, line)?);
+        }
+        "grab_pointers" => extracted.device_rules.grab_pointers = parse_bool(&value, line)?,
+        "notify_failures" => settings.notify_failures = parse_bool(&value, line)?,
+        "shell" => settings.shell = Shell::parse(&value).map_err(|e| syntax_error(line, e))?,
+        "input_state" => {
+            settings.input_state = match value.as_str() {
+                "global" => InputState::Global,
+                "device" => InputState::Device,
+                _ => return Err(syntax_error(line, "Expected global or device")),
+            };
+        }
+        _ => return Err(syntax_error(line, format!("Unknown setting: {}", name))),
+    }
+    Ok(())
+}
+
+fn parse_bool(value: &str, line: usize) -> Result<bool, ConfigError> {
+    match value {
+        "true" | "on" | "yes" => Ok(true),
+        "false" | "off" | "no" => Ok(false),
+        _ => Err(syntax_error(line, format!("Expected true or false, got: {}", value))),
+    }
+}
+
+fn parse_millis(value: &str, line: usize) -> Result<u64, ConfigError> {
+    value.parse().map_err(|_| syntax_error(line, format!("Expected milliseconds, got: {}", value)))
+}
+
+/// Expands the braces of a binding and its command and parses every resulting hotkey.
+fn parse_bindings(
+    binding: &str,
+    command: &str,
+    layout: &Layout,
+    custom: &[CustomModifier],
+    line: usize,
+) -> Result<Vec<Hotkey>, ConfigError> {
+    let bindings = expand_braces(binding).map_err(|e| syntax_error(line, e))?;
+    let commands = expand_braces(command).map_err(|e| syntax_error(line, e))?;
+    if commands.len() != 1 && commands.len() != bindings.len() {
+        return Err(syntax_error(
+            line,
+            format!("{} bindings but {} commands after expansion", bindings.len(), commands.len()),
+        ));
+    }
+
+    let mut hotkeys = Vec::with_capacity(bindings.len());
+    for (index, binding) in bindings.iter().enumerate() {
+        let steps = split_chord(binding);
+        let (last, steps) = steps.split_last().unwrap();
+        let chord = steps
+            .iter()
+            .map(|step| parse_keybinding(step, custom).map_err(|e| syntax_error(line, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (keybinding, trigger) =
+            parse_last_step(last, custom).map_err(|e| syntax_error(line, e))?;
+        if trigger != Trigger::Key && !chord.is_empty() {
+            return Err(syntax_error(line, "Only keys can end a chord"));
+        }
+        if !chord.is_empty() && (keybinding.taps > 1 || chord.iter().any(|step| step.taps > 1)) {
+            return Err(syntax_error(line, "Multi-tap combos can't be part of a chord"));
+        }
+        let mut hotkey = Hotkey::from_keybinding(keybinding, String::new());
+        hotkey.chord = chord;
+        hotkey.trigger = trigger;
+        parse_command(&mut hotkey, &commands[index % commands.len()], layout)
+            .map_err(|e| syntax_error(line, e))?;
+        hotkeys.push(hotkey);
+    }
+    Ok(hotkeys)
+}
+
+/// Parses the rest of a `tap_hold <key> <tap> <hold>` statement. The hold role is a key, a
+/// modifier name standing for its left key, or `mode <name>`.
+fn parse_dual_role<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<DualRole, String> {
+    let key = parse_key_or_modifier(words.next().ok_or("Missing dual-role key")?)?;
+    let tap = parse_key_or_modifier(words.next().ok_or("Missing tap key")?)?;
+    let hold = match words.next().ok_or("Missing hold role")? {
+        "mode" => Hold::Mode(words.next().ok_or("Missing mode name")?.to_string()),
+        name => Hold::Key(parse_key_or_modifier(name)?),
+    };
+    if let Some(word) = words.next() {
+        return Err(format!("Unexpected `{}` after the hold role", word));
+    }
+    Ok(DualRole { key, tap, hold })
+}
+
+/// Parses a key, or a modifier name standing for its left key.
+fn parse_key_or_modifier(name: &str) -> Result<Key, String> {
+    let name = name.trim();
+    parse_key(name).or_else(|| modifier_key(name)).ok_or_else(|| format!("Unknown key: {}", name))
+}
+
+/// The left key of a modifier, e.g. `KEY_LEFTCTRL` for `ctrl`.
+fn modifier_key(name: &str) -> Option<Key> {
+    match parse_modifier(name)? {
+        Modifier::Super => Some(Key::KEY_LEFTMETA),
+        Modifier::Alt => Some(Key::KEY_LEFTALT),
+        Modifier::Altgr => Some(Key::KEY_RIGHTALT),
+        Modifier::Control => Some(Key::KEY_LEFTCTRL),
+        Modifier::Shift => Some(Key::KEY_LEFTSHIFT),
+        Modifier::LeftSuper => Some(Key::KEY_LEFTMETA),
+        Modifier::RightSuper => Some(Key::KEY_RIGHTMETA),
+        Modifier::LeftControl => Some(Key::KEY_LEFTCTRL),
+        Modifier::RightControl => Some(Key::KEY_RIGHTCTRL),
+        Modifier::LeftShift => Some(Key::KEY_LEFTSHIFT),
+        Modifier::RightShift => Some(Key::KEY_RIGHTSHIFT),
+        Modifier::Any | Modifier::Custom(_) => None,
+    }
+}
+
+/// Parses the rest of a `switch <name> <on|off> [swallow]` binding, e.g. `switch lid on`.
+fn parse_switch<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Hotkey, String> {
+    let name = words.next().ok_or("Missing switch name")?;
+    let switch = SwitchType::from_str(&name.to_uppercase())
+        .or_else(|_| SwitchType::from_str(&format!("SW_{}", name.to_uppercase())))
+        .map_err(|_| format!("Unknown switch: {}", name))?;
+    let on = match words.next() {
+        Some("on") => true,
+        Some("off") => false,
+        _ => return Err(format!("Expected on or off after switch {}", name)),
+    };
+    // Switch events are passed on unless the binding swallows them
+    let mut keybinding = KeyBinding::new(Key::KEY_RESERVED, HashSet::new());
+    keybinding.send = match words.next() {
+        None => true,
+        Some("swallow") => false,
+        Some(option) => return Err(format!("Unknown switch option: {}", option)),
+    };
+    let mut hotkey = Hotkey::from_keybinding(keybinding, String::new());
+    hotkey.trigger = Trigger::Switch { switch, on };
+    Ok(hotkey)
+}
+
+/// Whether the binding uses keys that only swhkd knows, like mouse buttons and scroll wheels,
+/// or sided modifiers.
+fn has_extended_key(binding: &str) -> bool {
+    binding.split(|c: char| c.is_whitespace() || "+;{},~@".contains(c)).any(|token| {
+        let lower = token.to_lowercase();
+        ["button", "btn_", "scroll_", "dial_"].iter().any(|prefix| lower.starts_with(prefix))
+            || ["lalt", "ralt"].contains(&lower.as_str())
+            || parse_modifier(token).is_some_and(|modifier| modifier.generic() != modifier)
+    })
+}
+
+/// Whether the binding ends with a modifier instead of a key, e.g. `super` on its own.
+fn has_modifier_tap(binding: &str) -> bool {
+    let last = binding.rsplit(['+', '~', '@']).next().unwrap_or_default();
+    parse_tapped_modifier(last, &[]).is_some()
+}
+
+fn parse_tapped_modifier(name: &str, custom: &[CustomModifier]) -> Option<Modifier> {
+    lookup_modifier(name, custom).filter(|modifier| *modifier != Modifier::Any)
+}
+
+/// Whether the binding uses a modifier declared with `modifier`.
+fn has_custom_modifier(binding: &str, custom: &[CustomModifier]) -> bool {
+    binding
+        .split(|c: char| c.is_whitespace() || "+;{},~@".contains(c))
+        .any(|token| custom.iter().any(|modifier| modifier.name.eq_ignore_ascii_case(token)))
+}
+
+/// Looks up a built-in modifier or one declared with `modifier`.
+fn lookup_modifier(name: &str, custom: &[CustomModifier]) -> Option<Modifier> {
+    let name = name.trim();
+    parse_modifier(name).or_else(|| {
+        custom
+            .iter()
+            .position(|modifier| modifier.name.eq_ignore_ascii_case(name))
+            .map(Modifier::Custom)
+    })
+}
+
+/// Parses the rest of a `modifier <name> = <key>... [swallow]` statement, e.g.
+/// `modifier hyper = capslock swallow`.
+fn parse_custom_modifier(
+    declared: &[CustomModifier],
+    text: &str,
+) -> Result<CustomModifier, String> {
+    let Some((name, keys)) = text.split_once('=') else {
+        return Err("Expected `modifier <name> = <key>`".to_string());
+    };
+    let name = name.trim().to_lowercase();
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || "+;{},~@".contains(c)) {
+        return Err(format!("Invalid modifier name: {}", name));
+    }
+    if lookup_modifier(&name, declared).is_some() || parse_key(&name).is_some() {
+        return Err(format!("Modifier name is already taken: {}", name));
+    }
+    let mut words: Vec<&str> = keys.split_whitespace().collect();
+    let swallow = words.last() == Some(&"swallow");
+    if swallow {
+        words.pop();
+    }
+    if words.is_empty() {
+        return Err(format!("Missing the keys of modifier {}", name));
+    }
+    let keys = words
+        .iter()
+        .map(|word| parse_key(word).ok_or_else(|| format!("Unknown key: {}", word)))
+        .collect::<Result<_, _>>()?;
+    Ok(CustomModifier { name, keys, swallow })
+}
+
+fn has_directive(command: &str) -> bool {
+    command.split("&&").map(str::trim).any(|part| {
+        DIRECTIVES.iter().any(|directive| {
+            part.strip_prefix(directive)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+        })
+    })
+}
+
+/// Splits a binding into its chord steps, e.g. `super + w ; f` into `super + w` and `f`.
+/// A `;` directly following a `+`, or starting a step, is the semicolon key instead.
+fn split_chord(binding: &str) -> Vec<&str> {
+    let mut steps = Vec::new();
+    let mut step_start = 0;
+    let mut last = None;
+    for (index, c) in binding.char_indices() {
+        if c == ';' && last.is_some_and(|last| last != '+' && last != '\\') {
+            steps.push(&binding[step_start..index]);
+            step_start = index + 1;
+            last = None;
+            continue;
+        }
+        if !c.is_whitespace() {
+            last = Some(c);
+        }
+    }
+    steps.push(&binding[step_start..]);
+    steps
+}
+
+/// Expands sxhkd style sequences like `super + {_,shift + }{1-9}` into every combination.
+/// `_` stands for an empty element and `a-z` style ranges are expanded to single characters.
+fn expand_braces(text: &str) -> Result<Vec<String>, String> {
+    let mut expansions = vec![String::new()];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some('{' | '}' | ',')) => {
+                let escaped = chars.next().unwrap();
+                expansions.iter_mut().for_each(|e| e.push(escaped));
+            }
+            '{' => {
+                let mut elements = vec![String::new()];
+                loop {
+                    match chars.next() {
+                        None => return Err(format!("Unclosed brace in: {}", text)),
+                        Some('}') => break,
+                        Some(',') => elements.push(String::new()),
+                        Some('\\') => {
+                            if let Some(escaped) = chars.next() {
+                                elements.last_mut().unwrap().push(escaped);
+                            }
+                        }
+                        Some(c) => elements.last_mut().unwrap().push(c),
+                    }
+                }
+                let elements: Vec<String> =
+                    elements.iter().flat_map(|e| expand_element(e)).collect();
+                expansions = expansions
+                    .iter()
+                    .flat_map(|prefix| elements.iter().map(move |e| format!("{}{}", prefix, e)))
+                    .collect();
+            }
+            c => expansions.iter_mut().for_each(|e| e.push(c)),
+        }
+    }
+    Ok(expansions)
+}
+
+fn expand_element(element: &str) -> Vec<String> {
+    let trimmed = element.trim();
+    if trimmed == "_" {
+        return vec![String::new()];
+    }
+    let chars: Vec<char> = trimmed.chars().collect();
+    if let [from, '-', to] = chars[..] {
+        if from.is_ascii_alphanumeric() && to.is_ascii_alphanumeric() && from < to {
+            return (from..=to).map(String::from).collect();
+        }
+    }
+    vec![element.to_string()]
+}
+
+/// Splits the internal commands off a command and applies them to the hotkey.
+fn parse_command(hotkey: &mut Hotkey, command: &str, layout: &Layout) -> Result<(), String> {
+    let mut parts = Vec::new();
+    for part in command.split("&&").map(str::trim) {
+        if let Some(name) = part.strip_prefix("@enter ") {
+            hotkey.mode_instructions.push(ModeInstruction::Enter(name.trim().to_string()));
+        } else if part == "@escape" {
+            hotkey.mode_instructions.push(ModeInstruction::Escape);
+        } else if let Some(selectors) = part.strip_prefix("@device ") {
+            hotkey.devices.extend(DeviceSelector::parse_list(selectors)?);
+        } else if let Some(combo) = part.strip_prefix("@send ") {
+            let keys = combo.split('+').map(parse_key_or_modifier).collect::<Result<_, _>>()?;
+            hotkey.outputs.push(Output::Combo(keys));
+        } else if let Some(text) = part.strip_prefix("@type ") {
+            let steps = parse_text(text)?
+                .chars()
+                .map(|c| match layout.get(c) {
+                    Some(keys) => Ok(MacroStep::Combo(keys.to_vec())),
+                    None => Err(format!("No key for {:?} in the layout", c)),
+                })
+                .collect::<Result<_, _>>()?;
+            hotkey.outputs.push(Output::Macro(steps));
+        } else if let Some(steps) = part.strip_prefix("@macro ") {
+            hotkey.outputs.push(Output::Macro(parse_macro(steps)?));
+        } else if let Some(repeat) = part.strip_prefix("@repeat ") {
+            hotkey.repeat = Some(parse_repeat(repeat)?);
+        } else if let Some(millis) = part.strip_prefix("@hold ") {
+            let millis = millis
+                .trim()
+                .parse()
+                .map_err(|_| format!("Expected milliseconds, got: {}", millis))?;
+            hotkey.keybinding.hold = Some(Duration::from_millis(millis));
+        } else if part == "@single" {
+            hotkey.policy = Policy::Single;
+        } else if part == "@toggle" {
+            hotkey.policy = Policy::Toggle;
+        } else if let Some(text) = part.strip_prefix("@env ") {
+            hotkey.environment.extend(&parse_environment("env", text)?);
+        } else if let Some(text) = part.strip_prefix("@unset ") {
+            hotkey.environment.extend(&parse_environment("unset", text)?);
+        } else if let Some(text) = part.strip_prefix("@cwd ") {
+            hotkey.environment.extend(&parse_environment("cwd", text)?);
+        } else if let Some(shell) = part.strip_prefix("@shell ") {
+            hotkey.shell = Some(Shell::parse(shell)?);
+        } else if let Some(rest) =
+            part.strip_prefix("@exec").filter(|rest| rest.is_empty() || rest.starts_with(' '))
+        {
+            hotkey.shell = Some(Shell::Exec);
+            if !rest.trim().is_empty() {
+                parts.push(rest.trim());
+            }
+        } else if !part.is_empty() {
+            parts.push(part);
+        }
+    }
+    hotkey.command = parts.join(" && ");
+    if hotkey.shell == Some(Shell::Exec) {
+        supervisor::split_args(&hotkey.command)?;
+    }
+    Ok(())
+}
+
+/// Parses the rest of `env <name>=<value>...`, `unset <name>...` or `cwd <dir>`, both as
+/// statements and as the `@env`, `@unset` and `@cwd` internal commands. Values can be quoted.
+fn parse_environment(kind: &str, text: &str) -> Result<Environment, String> {
+    let args = supervisor::split_args(text)?;
+    if args.is_empty() {
+        return Err(format!("Missing arguments of {}", kind));
+    }
+    let mut environment = Environment::default();
+    match kind {
+        "env" => {
+            for arg in args {
+                let Some((name, value)) = arg.split_once('=').filter(|(name, _)| !name.is_empty())
+                else {
+                    return Err(format!("Expected <name>=<value>, got: {}", arg));
+                };
+                environment.vars.push((name.to_string(), Some(value.to_string())));
+            }
+        }
+        "unset" => environment.vars.extend(args.into_iter().map(|name| (name, None))),
+        _ => match <[String; 1]>::try_from(args) {
+            Ok([dir]) => environment.dir = Some(dir),
+            Err(_) => return Err("Expected a single directory".to_string()),
+        },
+    }
+    Ok(environment)
+}
+
+/// Parses `off`, `kernel`, `device` or `<delay> [<interval>]` in milliseconds, the interval
+/// defaulting to the delay.
+fn parse_repeat(text: &str) -> Result<Repeat, String> {
+    let millis = |value: &str| match value.parse() {
+        Ok(millis) if millis > 0 => Ok(Duration::from_millis(millis)),
+        _ => Err(format!("Expected a positive number of milliseconds, got: {}", value)),
+    };
+    match text.split_whitespace().collect::<Vec<_>>()[..] {
+        ["off"] => Ok(Repeat::Off),
+        ["kernel"] => Ok(Repeat::Kernel),
+        ["device"] => Ok(Repeat::Device),
+        [delay] => Ok(Repeat::Every { delay: millis(delay)?, interval: millis(delay)? }),
+        [delay, interval] => {
+            Ok(Repeat::Every { delay: millis(delay)?, interval: millis(interval)? })
+        }
+        _ => Err("Expected `off`, `kernel`, `device` or `<delay> [<interval>]`".to_string()),
+    }
+}
+
+/// Parses the text of `@type`. Double quoted text can contain `\"`, `\\`, `\n` and `\t` escapes.
+fn parse_text(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    let Some(quoted) = text.strip_prefix('"') else {
+        return Ok(text.to_string());
+    };
+    let mut chars = quoted.chars();
+    let mut parsed = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if chars.as_str().trim().is_empty() => return Ok(parsed),
+            '\\' => match chars.next() {
+                Some('n') => parsed.push('\n'),
+                Some('t') => parsed.push('\t'),
+                Some(c) => parsed.push(c),
+                None => break,
+            },
+            c => parsed.push(c),
+        }
+    }
+    Err(format!("Unclosed quote in: {}", text))
+}
+
+/// Parses the steps of `@macro`, which are combos such as `ctrl+c` and delays such as `100ms`.
+fn parse_macro(steps: &str) -> Result<Vec<MacroStep>, String> {
+    steps
+        .split_whitespace()
+        .map(|step| match step.strip_suffix("ms").map(str::parse) {
+            Some(Ok(millis)) => Ok(MacroStep::Delay(Duration::from_millis(millis))),
+            _ => Ok(MacroStep::Combo(
+                step.split('+').map(parse_key_or_modifier).collect::<Result<_, _>>()?,
+            )),
+        })
+        .collect()
+}
+
+/// Parses a `layout <char> <combo>` statement, e.g. `layout z shift+y`. `space` stands for
+/// the space character.
+fn parse_layout(layout: &mut Layout, mapping: &str) -> Result<(), String> {
+    let mut words = mapping.split_whitespace();
+    let (Some(c), Some(combo), None) = (words.next(), words.next(), words.next()) else {
+        return Err("Expected `layout <char> <combo>`".to_string());
+    };
+    let mut chars = c.chars();
+    let c = match (chars.next(), chars.next()) {
+        _ if c == "space" => ' ',
+        (Some(c), None) => c,
+        _ => return Err(format!("Expected a single character, got: {}", c)),
+    };
+    let keys = combo.split('+').map(parse_key_or_modifier).collect::<Result<_, _>>()?;
+    layout.set(c, keys);
+    Ok(())
+}
+
+/// Parses a single `modifier + ... + key` combination, with the optional `~` and `@` prefixes.
+pub fn parse_keybinding(text: &str, custom: &[CustomModifier]) -> Result<KeyBinding, String> {
+    let (key, mut keybinding) = parse_combination(text, custom)?;
+    keybinding.keysym = parse_key(key).ok_or_else(|| format!("Unknown key: {}", key))?;
+    Ok(keybinding)
+}
+
+/// Parses the last step of a binding, which can also end with a scroll direction or a tapped
+/// modifier instead of a key.
+fn parse_last_step(text: &str, custom: &[CustomModifier]) -> Result<(KeyBinding, Trigger), String> {
+    let (key, mut keybinding) = parse_combination(text, custom)?;
+    if let Some(modifier) = parse_tapped_modifier(key, custom) {
+        if keybinding.taps > 1 {
+            return Err(format!("Modifier taps can't be multi-tap: {}", text.trim()));
+        }
+        if keybinding.on_release {
+            return Err(format!("Modifier taps always fire on release: {}", text.trim()));
+        }
+        return Ok((keybinding, Trigger::ModifierTap(modifier)));
+    }
+    let Some(scroll) = parse_scroll(key) else {
+        keybinding.keysym = parse_key(key).ok_or_else(|| format!("Unknown key: {}", key))?;
+        return Ok((keybinding, Trigger::Key));
+    };
+    if keybinding.taps > 1 {
+        return Err(format!("Scroll bindings can't be multi-tap: {}", text.trim()));
+    }
+    if keybinding.on_release {
+        return Err(format!("Scroll bindings can't fire on release: {}", text.trim()));
+    }
+    Ok((keybinding, Trigger::Scroll(scroll)))
+}
+
+/// Splits a combination into its key name and a keybinding holding everything but the key.
+fn parse_combination<'a>(
+    text: &'a str,
+    custom: &[CustomModifier],
+) -> Result<(&'a str, KeyBinding), String> {
+    let mut text = text.trim();
+    let mut taps = 1;
+    if let Some((count, rest)) = strip_taps(text) {
+        taps = count;
+        text = rest;
+    }
+    let mut send = false;
+    let mut on_release = false;
+    loop {
+        if let Some(rest) = text.strip_prefix('~') {
+            send = true;
+            text = rest.trim_start();
+        } else if let Some(rest) = text.strip_prefix('@') {
+            on_release = true;
+            text = rest.trim_start();
+        } else {
+            break;
+        }
+    }
+
+    let tokens: Vec<&str> = text.split('+').map(str::trim).collect();
+    let (key, modifiers) = tokens.split_last().unwrap();
+    let modifiers = modifiers
+        .iter()
+        .map(|m| lookup_modifier(m, custom).ok_or_else(|| format!("Unknown modifier: {}", m)))
+        .collect::<Result<HashSet<_>, _>>()?;
+
+    if taps > 1 && on_release {
+        return Err(format!("Multi-tap bindings can't fire on release: {}", text));
+    }
+    Ok((
+        key,
+        KeyBinding { keysym: Key::KEY_RESERVED, modifiers, send, on_release, hold: None, taps },
+    ))
+}
+
+/// Splits the tap count off a multi-tap combination such as `2x super + e`.
+fn strip_taps(text: &str) -> Option<(u32, &str)> {
+    let (count, rest) = text.split_once('x')?;
+    let count = count.parse().ok().filter(|&count| count > 0)?;
+    rest.starts_with(char::is_whitespace).then(|| (count, rest.trim_start()))
+}
+
+/// Parses scroll wheel and dial directions. X11 button numbers 4 to 7 are the scroll wheel too.
+pub fn parse_scroll(name: &str) -> Option<Scroll> {
+    match name.trim().to_lowercase().as_str() {
+        "scroll_up" | "button4" => Some(Scroll::Up),
+        "scroll_down" | "button5" => Some(Scroll::Down),
+        "scroll_left" | "button6" => Some(Scroll::Left),
+        "scroll_right" | "button7" => Some(Scroll::Right),
+        "dial_cw" => Some(Scroll::DialCw),
+        "dial_ccw" => Some(Scroll::DialCcw),
+        _ => None,
+    }
+}
+
+/// Parses a modifier name. The sided ones are only known to swhkd, the others are looked up in
+/// sweet.
+pub fn parse_modifier(name: &str) -> Option<Modifier> {
+    match name.trim().to_lowercase().as_str() {
+        "lsuper" => Some(Modifier::LeftSuper),
+        "rsuper" => Some(Modifier::RightSuper),
+        "lctrl" | "lcontrol" => Some(Modifier::LeftControl),
+        "rctrl" | "rcontrol" => Some(Modifier::RightControl),
+        "lshift" => Some(Modifier::LeftShift),
+        "rshift" => Some(Modifier::RightShift),
+        // Alt is only the left key already, the right one being AltGr
+        "lalt" => Some(Modifier::Alt),
+        "ralt" => Some(Modifier::Altgr),
+        name => {
+            let combo = sweet_combo(&format!("{} + a", name))?;
+            let mut modifiers = combo.modifiers.iter();
+            match (modifiers.next(), modifiers.next()) {
+                (Some(modifier), None) => Modifier::from_sweet(modifier),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Parses the key names listed in swhkd-keys(5), which are looked up in sweet. X11 style mouse
+/// button numbers and raw evdev names like `KEY_F13` are only known to swhkd.
+pub fn parse_key(name: &str) -> Option<Key> {
+    let name = name.trim();
+    let key = match name.to_lowercase().as_str() {
+        // 4 to 7 are the scroll wheel, see parse_scroll
+        "button1" => Key::BTN_LEFT,
+        "button2" => Key::BTN_MIDDLE,
+        "button3" => Key::BTN_RIGHT,
+        "button8" => Key::BTN_SIDE,
+        "button9" => Key::BTN_EXTRA,
+        lower => {
+            let upper = lower.to_uppercase();
+            if upper.starts_with("KEY_") || upper.starts_with("BTN_") {
+                return Key::from_str(&upper).ok();
+            }
+            let combo = sweet_combo(name)?;
+            if !combo.modifiers.is_empty() || combo.key.attribute != KeyAttribute::None {
+                return None;
+            }
+            combo.key.key
+        }
+    };
+    Some(key)
+}
+
+/// Lets sweet parse a single combination, so that key and modifier names are the ones sweet
+/// knows instead of a copy of its tables.
+fn sweet_combo(text: &str) -> Option<Definition> {
+    // Braces, escapes and line breaks would turn it into something else than a single combo
+    if text.contains(|c: char| "{}\\\n".contains(c)) {
+        return None;
+    }
+    let parsed = SwhkdParser::from(ParserInput::Raw(&format!("{}\n\t:\n", text))).ok()?;
+    match &parsed.bindings[..] {
+        [binding] => Some(binding.definition.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{DeviceClass, DeviceInfo};
+
+    fn extract_one(config: &str) -> Result<Extracted, ConfigError> {
+        extract(&[(PathBuf::from("swhkdrc"), config.to_string())])
+    }
+
+    #[test]
+    fn expand_braces_combines_every_element() {
+        assert_eq!(
+            expand_braces("super + {_,shift + }{1-3}").unwrap(),
+            [
+                "super + 1",
+                "super + 2",
+                "super + 3",
+                "super + shift + 1",
+                "super + shift + 2",
+                "super + shift + 3"
+            ]
+        );
+        assert_eq!(expand_braces("echo {a,b}").unwrap(), ["echo a", "echo b"]);
+        assert_eq!(expand_braces(r"echo \{a\,b\}").unwrap(), ["echo {a,b}"]);
+        assert!(expand_braces("super + {a,b").is_err());
+    }
+
+    #[test]
+    fn split_chord_keeps_the_semicolon_key() {
+        assert_eq!(split_chord("super + w ; f"), ["super + w ", " f"]);
+        assert_eq!(split_chord("super + ;"), ["super + ;"]);
+        assert_eq!(split_chord("super + w ; ;"), ["super + w ", " ;"]);
+        assert_eq!(split_chord(r"super + \;"), [r"super + \;"]);
+    }
+
+    #[test]
+    fn strip_taps_needs_a_count_and_a_space() {
+        assert_eq!(strip_taps("2x super + e"), Some((2, "super + e")));
+        assert_eq!(strip_taps("0x super + e"), None);
+        assert_eq!(strip_taps("x super + e"), None);
+        assert_eq!(strip_taps("super + x"), None);
+        assert_eq!(strip_taps("2xsuper + e"), None);
+    }
+
+    #[test]
+    fn names_are_looked_up_in_sweet_first() {
+        assert_eq!(parse_key("ReTuRn"), Some(Key::KEY_ENTER));
+        assert_eq!(parse_key("KEY_F13"), Some(Key::KEY_F13));
+        assert_eq!(parse_key("button3"), Some(Key::BTN_RIGHT));
+        assert_eq!(parse_key("super"), None);
+        assert_eq!(parse_key("{a,b}"), None);
+        assert_eq!(parse_modifier("mod4"), Some(Modifier::Super));
+        assert_eq!(parse_modifier("rctrl"), Some(Modifier::RightControl));
+        assert_eq!(parse_modifier("a"), None);
+    }
+
+    #[test]
+    fn extract_leaves_plain_bindings_to_sweet() {
+        let config =
+            "super + a\n\techo a\n\nsuper + w ; f\n\techo chord\nset multi_tap_window 200\n";
+        let extracted = extract_one(config).unwrap();
+        assert_eq!(extracted.sources, ["super + a\n\techo a\n\n\n\n"]);
+        assert_eq!(extracted.settings.multi_tap_window, Duration::from_millis(200));
+        let [(None, hotkey)] = &extracted.hotkeys[..] else {
+            panic!("expected a single hotkey outside of modes: {:?}", extracted.hotkeys);
+        };
+        assert_eq!(hotkey.keybinding.keysym, Key::KEY_F);
+        assert_eq!(hotkey.chord.len(), 1);
+        assert_eq!(hotkey.chord[0].keysym, Key::KEY_W);
+        assert_eq!(hotkey.command, "echo chord");
+    }
+
+    #[test]
+    fn extract_keeps_the_mode_of_extracted_bindings() {
+        let config = "mode music\nsuper + {a,b}\n\t@single && mpc {play,stop}\nendmode\n";
+        let extracted = extract_one(config).unwrap();
+        assert_eq!(extracted.sources, ["mode music\n\n\nendmode"]);
+        let commands: Vec<_> = extracted
+            .hotkeys
+            .iter()
+            .map(|(mode, hotkey)| (mode.as_deref(), hotkey.command.as_str(), hotkey.policy))
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                (Some("music"), "mpc play", Policy::Single),
+                (Some("music"), "mpc stop", Policy::Single)
+            ]
+        );
+    }
+
+    #[test]
+    fn extract_reports_the_line_of_errors() {
+        match extract_one("super + a\n\techo\nremap a\n") {
+            Err(ConfigError::Syntax { line, .. }) => assert_eq!(line, 3),
+            other => panic!("expected a syntax error: {:?}", other.map(|e| e.sources)),
+        }
+    }
+
+    #[test]
+    fn device_scoped_hotkeys_only_match_their_devices() {
+        let extracted =
+            extract_one("super + a\n\t@device name:\"*Macro*\" id:1050 && echo a\n").unwrap();
+        let [(None, hotkey)] = &extracted.hotkeys[..] else {
+            panic!("expected a single hotkey outside of modes: {:?}", extracted.hotkeys);
+        };
+        let device = |name: &str, vendor: u16| DeviceInfo {
+            path: "/dev/input/event3".to_string(),
+            name: name.to_string(),
+            phys: String::new(),
+            uniq: String::new(),
+            bus: 3,
+            vendor,
+            product: 0x0407,
+            classes: vec![DeviceClass::Keyboard],
+        };
+        assert!(hotkey.matches_device(&device("USB Macro Pad", 0x046d)));
+        assert!(hotkey.matches_device(&device("YubiKey", 0x1050)));
+        assert!(!hotkey.matches_device(&device("AT Translated Keyboard", 0x0001)));
+        let unscoped = Hotkey::from_keybinding(hotkey.keybinding.clone(), "echo".to_string());
+        assert!(unscoped.matches_device(&device("AT Translated Keyboard", 0x0001)));
+    }
+
+    #[test]
+    fn custom_modifiers_only_swallow_their_keys_when_asked() {
+        let hyper = parse_custom_modifier(&[], "Hyper = capslock swallow").unwrap();
+        assert_eq!(
+            hyper,
+            CustomModifier {
+                name: "hyper".to_string(),
+                keys: vec![Key::KEY_CAPSLOCK],
+                swallow: true
+            }
+        );
+        let layer = parse_custom_modifier(&[], "layer = KEY_F13 KEY_F14").unwrap();
+        assert_eq!((layer.keys.len(), layer.swallow), (2, false));
+        assert!(parse_custom_modifier(&[], "layer = swallow").is_err());
+        assert!(parse_custom_modifier(&[hyper], "hyper = KEY_F13").is_err());
+    }
+
+    #[test]
+    fn custom_modifiers_are_described_by_their_name() {
+        let extracted =
+            extract_one("modifier hyper = capslock\nhyper + b\n\techo b\nhyper\n\techo\n").unwrap();
+        let labels: Vec<String> = extracted
+            .hotkeys
+            .iter()
+            .map(|(_, hotkey)| hotkey.describe(&extracted.custom_modifiers))
+            .collect();
+        assert_eq!(labels, ["hyper + KEY_B", "hyper"]);
+    }
+
+    #[test]
+    fn extract_reads_included_files_like_the_main_one() {
+        let main = "modifier hyper = capslock\ninclude /etc/swhkd/extra\nsuper + a\n\techo a\n";
+        let extra = "set multi_tap_window 200\nhyper + b\n\t@single && echo b\n";
+        assert_eq!(includes(main), [PathBuf::from("/etc/swhkd/extra")]);
+        let files = [
+            (PathBuf::from("swhkdrc"), main.to_string()),
+            (PathBuf::from("/etc/swhkd/extra"), extra.to_string()),
+        ];
+        let extracted = extract(&files).unwrap();
+        assert_eq!(extracted.sources, ["\n\nsuper + a\n\techo a", "\n\n"]);
+        assert_eq!(extracted.settings.multi_tap_window, Duration::from_millis(200));
+        let [(None, hotkey)] = &extracted.hotkeys[..] else {
+            panic!("expected a single hotkey outside of modes: {:?}", extracted.hotkeys);
+        };
+        assert_eq!(hotkey.command, "echo b");
+        assert_eq!(hotkey.policy, Policy::Single);
+        assert_eq!(hotkey.keybinding.modifiers, HashSet::from([Modifier::Custom(0)]));
+
+        let files = [
+            (PathBuf::from("swhkdrc"), "include /etc/swhkd/extra\n".to_string()),
+            (PathBuf::from("/etc/swhkd/extra"), "\nremap a\n".to_string()),
+        ];
+        match extract(&files) {
+            Err(ConfigError::Syntax { path, line, .. }) => {
+                assert_eq!((path.as_path(), line), (Path::new("/etc/swhkd/extra"), 2))
+            }
+            other => panic!("expected a syntax error: {:?}", other.map(|e| e.sources)),
+        }
+    }
+}
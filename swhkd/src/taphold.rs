@@ -0,0 +1,194 @@
+use evdev::Key;
+use std::collections::HashMap;
+
+/// A key that acts as `tap` when tapped and as `hold` while held, e.g. caps lock tapping to
+/// escape and holding to control.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DualRole {
+    pub key: Key,
+    pub tap: Key,
+    pub hold: Hold,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hold {
+    /// Holds down another key, usually a modifier.
+    Key(Key),
+    /// Enters a mode for as long as the key is held.
+    Mode(String),
+}
+
+/// What the daemon does in place of a key event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// A key event with the given value that is handled like one read from the device.
+    Key(Key, i32),
+    EnterMode(String),
+    ExitMode(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// The event doesn't concern any dual-role key.
+    Pass,
+    /// The event is consumed and the actions, if any, take its place.
+    Replace(Vec<Action>),
+    /// The actions have to be carried out before the event itself is handled.
+    Prepend(Vec<Action>),
+}
+
+/// Resolves dual-role keys. The press of a dual-role key is buffered until the key is released,
+/// which makes it a tap, or until another key is pressed or the timeout passes, which makes it
+/// a hold.
+#[derive(Debug, Default)]
+pub struct TapHold {
+    roles: HashMap<Key, DualRole>,
+    /// The dual-role key whose press is buffered.
+    pending: Option<Key>,
+    /// Dual-role keys that resolved to their hold role and are still held down.
+    held: HashMap<Key, Hold>,
+}
+
+impl TapHold {
+    pub fn new(roles: &[DualRole]) -> Self {
+        TapHold {
+            roles: roles.iter().map(|role| (role.key, role.clone())).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn feed(&mut self, key: Key, value: i32) -> Outcome {
+        if let Some(pending) = self.pending {
+            if key == pending {
+                // Repeats of the buffered press are dropped, a release makes it a tap
+                if value != 0 {
+                    return Outcome::Replace(vec![]);
+                }
+                self.pending = None;
+                let tap = self.roles[&key].tap;
+                return Outcome::Replace(vec![Action::Key(tap, 1), Action::Key(tap, 0)]);
+            }
+            if value == 1 {
+                return Outcome::Prepend(self.resolve_hold());
+            }
+            return Outcome::Pass;
+        }
+
+        if let Some(hold) = self.held.get(&key) {
+            if value != 0 {
+                return Outcome::Replace(vec![]);
+            }
+            let action = match hold {
+                Hold::Key(hold) => Action::Key(*hold, 0),
+                Hold::Mode(name) => Action::ExitMode(name.clone()),
+            };
+            self.held.remove(&key);
+            return Outcome::Replace(vec![action]);
+        }
+
+        if value == 1 && self.roles.contains_key(&key) {
+            self.pending = Some(key);
+            return Outcome::Replace(vec![]);
+        }
+        Outcome::Pass
+    }
+
+    /// Resolves the buffered press as a hold, e.g. when the timeout passed.
+    pub fn resolve_hold(&mut self) -> Vec<Action> {
+        let Some(key) = self.pending.take() else {
+            return vec![];
+        };
+        let hold = self.roles[&key].hold.clone();
+        let action = match &hold {
+            Hold::Key(hold) => Action::Key(*hold, 1),
+            Hold::Mode(name) => Action::EnterMode(name.clone()),
+        };
+        self.held.insert(key, hold);
+        vec![action]
+    }
+
+    /// Lets go of the hold roles of the dual-role keys that are held down, e.g. before the roles
+    /// are replaced on a reload. A buffered press is dropped.
+    pub fn release_held(&mut self) -> Vec<Action> {
+        self.pending = None;
+        self.held
+            .drain()
+            .map(|(_, hold)| match hold {
+                Hold::Key(hold) => Action::Key(hold, 0),
+                Hold::Mode(name) => Action::ExitMode(name),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caps_lock() -> TapHold {
+        TapHold::new(&[DualRole {
+            key: Key::KEY_CAPSLOCK,
+            tap: Key::KEY_ESC,
+            hold: Hold::Key(Key::KEY_LEFTCTRL),
+        }])
+    }
+
+    #[test]
+    fn release_before_other_keys_is_a_tap() {
+        let mut tap_hold = caps_lock();
+        assert_eq!(tap_hold.feed(Key::KEY_CAPSLOCK, 1), Outcome::Replace(vec![]));
+        assert!(tap_hold.is_pending());
+        assert_eq!(tap_hold.feed(Key::KEY_CAPSLOCK, 2), Outcome::Replace(vec![]));
+        assert_eq!(
+            tap_hold.feed(Key::KEY_CAPSLOCK, 0),
+            Outcome::Replace(vec![Action::Key(Key::KEY_ESC, 1), Action::Key(Key::KEY_ESC, 0)])
+        );
+        assert!(!tap_hold.is_pending());
+    }
+
+    #[test]
+    fn other_key_press_makes_it_a_hold() {
+        let mut tap_hold = caps_lock();
+        tap_hold.feed(Key::KEY_CAPSLOCK, 1);
+        assert_eq!(
+            tap_hold.feed(Key::KEY_C, 1),
+            Outcome::Prepend(vec![Action::Key(Key::KEY_LEFTCTRL, 1)])
+        );
+        assert_eq!(tap_hold.feed(Key::KEY_C, 0), Outcome::Pass);
+        assert_eq!(tap_hold.feed(Key::KEY_CAPSLOCK, 2), Outcome::Replace(vec![]));
+        assert_eq!(
+            tap_hold.feed(Key::KEY_CAPSLOCK, 0),
+            Outcome::Replace(vec![Action::Key(Key::KEY_LEFTCTRL, 0)])
+        );
+        assert_eq!(tap_hold.feed(Key::KEY_C, 1), Outcome::Pass);
+    }
+
+    #[test]
+    fn release_held_lets_go_of_holds() {
+        let mut tap_hold = caps_lock();
+        tap_hold.feed(Key::KEY_CAPSLOCK, 1);
+        tap_hold.resolve_hold();
+        assert_eq!(tap_hold.release_held(), [Action::Key(Key::KEY_LEFTCTRL, 0)]);
+        assert_eq!(tap_hold.release_held(), []);
+    }
+
+    #[test]
+    fn timeout_makes_it_a_hold() {
+        let mut tap_hold = TapHold::new(&[DualRole {
+            key: Key::KEY_SPACE,
+            tap: Key::KEY_SPACE,
+            hold: Hold::Mode("nav".to_string()),
+        }]);
+        tap_hold.feed(Key::KEY_SPACE, 1);
+        assert_eq!(tap_hold.resolve_hold(), [Action::EnterMode("nav".to_string())]);
+        assert_eq!(tap_hold.resolve_hold(), []);
+        assert_eq!(
+            tap_hold.feed(Key::KEY_SPACE, 0),
+            Outcome::Replace(vec![Action::ExitMode("nav".to_string())])
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use clap::{Parser, Subcommand};
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+/// Control a running swhkd instance
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Set a custom control socket path.
+    #[arg(short, long, value_name = "FILE", default_value = "/run/swhkd/control.sock")]
+    socket: PathBuf,
+
+    /// Set a custom mode events socket path.
+    #[arg(short, long, value_name = "FILE", default_value = "/run/swhkd/events.sock")]
+    events_socket: PathBuf,
+
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Reload the config file
+    Reload,
+    /// Pause hotkey checking and release the grabbed devices
+    Pause,
+    /// Resume hotkey checking
+    Resume,
+    /// Show whether swhkd is paused, the active mode and the number of devices
+    Status,
+    /// List the devices grabbed by swhkd
+    ListDevices,
+    /// Show the mode stack, bottom first
+    ShowModeStack,
+    /// Show how often the commands of each hotkey ran, failed and how the last one ended
+    ListProcesses,
+    /// Print every mode change as a line of JSON until interrupted
+    Subscribe,
+}
+
+impl Cmd {
+    fn request(&self) -> &'static str {
+        match self {
+            Cmd::Subscribe => unreachable!("subscribe does not use the control socket"),
+            Cmd::Reload => "reload",
+            Cmd::Pause => "pause",
+            Cmd::Resume => "resume",
+            Cmd::Status => "status",
+            Cmd::ListDevices => "list-devices",
+            Cmd::ShowModeStack => "show-mode-stack",
+            Cmd::ListProcesses => "list-processes",
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    if let Cmd::Subscribe = args.command {
+        subscribe(&args.events_socket);
+    }
+
+    let mut stream = match UnixStream::connect(&args.socket) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {}", args.socket.display(), e);
+            exit(1);
+        }
+    };
+
+    if let Err(e) = writeln!(stream, "{}", args.command.request()) {
+        eprintln!("Failed to send request: {}", e);
+        exit(1);
+    }
+
+    let mut reply = String::new();
+    if let Err(e) = BufReader::new(&stream).read_line(&mut reply) {
+        eprintln!("Failed to read reply: {}", e);
+        exit(1);
+    }
+
+    let reply = reply.trim_end();
+    println!("{}", reply);
+
+    // Replies are always a single JSON object that starts with the `ok` field.
+    if !reply.starts_with("{\"ok\":true") {
+        exit(1);
+    }
+}
+
+/// Streams the mode events of swhkd to stdout. Doesn't require root.
+fn subscribe(path: &Path) -> ! {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {}", path.display(), e);
+            exit(1);
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        match line {
+            Ok(line) => println!("{}", line),
+            Err(e) => {
+                eprintln!("Failed to read event: {}", e);
+                exit(1);
+            }
+        }
+    }
+    exit(0);
+}